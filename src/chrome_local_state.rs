@@ -19,9 +19,22 @@ type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ChromeProfile {
-    hosted_domain: String,
-    name: Option<String>,
-    shortcut_name: Option<String>,
+    pub hosted_domain: String,
+    pub name: Option<String>,
+    pub shortcut_name: Option<String>,
+    /// The account the profile is signed in to, if any
+    pub user_name: Option<String>,
+}
+
+impl ChromeProfile {
+    /// The hosted domain of the profile, if it's signed in to a Google Workspace account
+    pub fn hosted_domain(&self) -> Option<&str> {
+        if self.hosted_domain.is_empty() || self.hosted_domain == NO_HOSTED_DOMAIN {
+            None
+        } else {
+            Some(&self.hosted_domain)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -29,18 +42,65 @@ pub struct ProfilesData {
     info_cache: HashMap<String, ChromeProfile>,
 }
 
+/// Chrome's placeholder for profiles that aren't signed in to a Google Workspace account
+const NO_HOSTED_DOMAIN: &str = "NO_HOSTED_DOMAIN";
+
+/// Match `domain` against `pattern`, where any `*` in the pattern matches any sequence of characters.
+fn hosted_domain_matches(pattern: &str, domain: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let domain = domain.to_ascii_lowercase();
+
+    let mut parts = pattern.split('*');
+    let prefix = parts.next().unwrap_or_default();
+    let Some(mut remaining) = domain.strip_prefix(prefix) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((suffix, middle)) = parts.split_last() else {
+        // There was no wildcard in the pattern, so it needs to be an exact match
+        return remaining.is_empty();
+    };
+
+    // Wildcards should only match profiles that actually have a hosted domain
+    if domain.is_empty() || domain.eq_ignore_ascii_case(NO_HOSTED_DOMAIN) {
+        return false;
+    }
+
+    for part in middle {
+        match remaining.find(part) {
+            Some(index) => remaining = &remaining[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    remaining.ends_with(suffix)
+}
+
 impl ProfilesData {
+    /// All the profiles along with the name of their profile directory, sorted by the directory name
+    pub fn profiles(&self) -> Vec<(&String, &ChromeProfile)> {
+        let mut profiles: Vec<_> = self.info_cache.iter().collect();
+        profiles.sort_by_key(|(profile_name, _)| *profile_name);
+        profiles
+    }
+
+    /// Find all the profiles whose hosted domain matches `hosted_domain`, which can contain `*` wildcards.
     pub fn profiles_by_hosted_domain(&self, hosted_domain: &str) -> Vec<&String> {
-        self.info_cache
+        let mut profiles: Vec<&String> = self
+            .info_cache
             .iter()
             .filter_map(|(profile_name, profile)| {
-                if profile.hosted_domain == hosted_domain {
+                if hosted_domain_matches(hosted_domain, &profile.hosted_domain) {
                     Some(profile_name)
                 } else {
                     None
                 }
             })
-            .collect()
+            .collect();
+        // Sort the matches so that we consistently pick the same profile when there are several
+        profiles.sort();
+        profiles
     }
     pub fn profile_by_name(&self, name: &str) -> Option<&str> {
         // Prefer direct profile name matches
@@ -75,3 +135,62 @@ pub fn read_profiles_from_file<P: AsRef<Path>>(path: P) -> Result<ProfilesData>
 
     Ok(state.profile)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_hosted_domains() {
+        assert!(hosted_domain_matches("corp.com", "corp.com"));
+        assert!(hosted_domain_matches("Corp.com", "corp.COM"));
+        assert!(!hosted_domain_matches("corp.com", "eu.corp.com"));
+        assert!(!hosted_domain_matches("corp.com", "corp.co"));
+        // Signed out profiles can be picked by asking for Chrome's placeholder
+        assert!(hosted_domain_matches(NO_HOSTED_DOMAIN, NO_HOSTED_DOMAIN));
+    }
+
+    #[test]
+    fn wildcard_hosted_domains() {
+        assert!(hosted_domain_matches("*.corp.com", "eu.corp.com"));
+        assert!(!hosted_domain_matches("*.corp.com", "corp.com"));
+        assert!(hosted_domain_matches("corp.*", "corp.co.uk"));
+        assert!(hosted_domain_matches("*corp*", "mycorp.example.com"));
+        assert!(hosted_domain_matches("eu.*.corp.*", "eu.sales.corp.net"));
+        assert!(!hosted_domain_matches("eu.*.corp.*", "us.sales.corp.net"));
+        assert!(!hosted_domain_matches("a*a", "a"));
+    }
+
+    #[test]
+    fn wildcards_skip_signed_out_profiles() {
+        assert!(!hosted_domain_matches("*", NO_HOSTED_DOMAIN));
+        assert!(!hosted_domain_matches("*", ""));
+        assert!(!hosted_domain_matches("NO_*", NO_HOSTED_DOMAIN));
+        assert!(hosted_domain_matches("*", "corp.com"));
+    }
+
+    #[test]
+    fn profiles_by_hosted_domain_are_sorted() {
+        let profiles: ProfilesData = serde_json::from_value(serde_json::json!({
+            "info_cache": {
+                "Profile 3": { "hosted_domain": "eu.corp.com" },
+                "Default": { "hosted_domain": NO_HOSTED_DOMAIN },
+                "Profile 1": { "hosted_domain": "us.corp.com" },
+                "Profile 2": { "hosted_domain": "other.com" },
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            profiles.profiles_by_hosted_domain("*.corp.com"),
+            ["Profile 1", "Profile 3"]
+        );
+        assert_eq!(
+            profiles.profiles_by_hosted_domain("*"),
+            ["Profile 1", "Profile 2", "Profile 3"]
+        );
+        assert_eq!(
+            profiles.profiles_by_hosted_domain(NO_HOSTED_DOMAIN),
+            ["Default"]
+        );
+    }
+}
@@ -1,17 +1,25 @@
 #![allow(dead_code)]
 
-use log::trace;
+use log::{error, trace, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use schemars::{json_schema, JsonSchema, Schema};
 use webextension_pattern::Pattern;
 
 use crate::{
     chrome_local_state::{self, read_profiles_from_file},
-    os::get_chrome_local_state_path,
+    firefox_profiles::{self, read_profiles_from_dir},
+    os::{get_chrome_local_state_path, get_gecko_data_path, get_policy_path},
+    presets::{self, find_preset, Preset},
 };
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
-use std::fs::File;
-use std::io::BufReader;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
 use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::LazyLock,
+};
 use thiserror::Error;
 use url::Url;
 
@@ -21,23 +29,112 @@ pub enum Error {
     InvalidFile(#[source] std::io::Error),
     #[error("could not parse configuration file")]
     InvalidJson(#[source] serde_json::Error),
+    #[error("could not parse configuration file")]
+    InvalidJson5(#[source] json5::Error),
+    #[error("could not parse configuration file")]
+    InvalidToml(#[source] toml::de::Error),
+    #[error("could not parse configuration file")]
+    InvalidYaml(#[source] serde_yaml::Error),
     #[error("could not find declaration of profile {0}")]
     MissingProfile(String),
     #[error("unable to retrieve path for Chrome's Local State")]
     CantLocateChromeLocalState,
     #[error("unable to parse Chrome's Local State")]
     CantParseChromeLocalState(#[source] chrome_local_state::Error),
-    #[error("no profile in Chrome's Local State matched domain '{0}' specified in config")]
-    InvalidHostedDomain(String),
+    #[error("no profile in Chrome's Local State matched domain(s) {0:?} specified in config")]
+    InvalidHostedDomain(Vec<String>),
     #[error("no profile in Chrome's Local State matched name '{0}' specified in config")]
     InvalidProfileName(String),
+    #[error("unable to retrieve path for Firefox's profiles.ini")]
+    CantLocateFirefoxProfiles,
+    #[error("unable to parse Firefox's profiles.ini")]
+    CantParseFirefoxProfiles(#[source] firefox_profiles::Error),
+    #[error("no profile in Firefox's profiles.ini matched name '{0}' specified in config")]
+    InvalidFirefoxProfileName(String),
+    #[error("Firefox profile directory {0:?} specified in config does not exist")]
+    InvalidFirefoxProfilePath(PathBuf),
+    #[error("profile '{0}' is invalid")]
+    InvalidProfile(String, #[source] Box<Error>),
+    #[error("profile '{0}' falls back to itself through {1:?}")]
+    FallbackCycle(String, Vec<String>),
+    #[error("profile '{0}' extends unknown profile or template '{1}'")]
+    UnknownParentProfile(String, String),
+    #[error("profile '{0}' extends itself through {1:?}")]
+    ExtendsCycle(String, Vec<String>),
+    #[error("override #{0} has no `os`, `hostname` or `username` condition, so it would apply on every machine")]
+    UnconditionalOverride(usize),
+    #[error("override #{0} has unknown setting '{1}'")]
+    UnknownOverrideSetting(usize, String),
+    #[error("line {1} of rules file {0:?} is not of the form `pattern => profile`")]
+    InvalidRuleSyntax(PathBuf, usize),
+    #[error("line {1} of rules file {0:?} has an invalid pattern")]
+    InvalidRulePattern(PathBuf, usize, #[source] webextension_pattern::Error),
+    #[error("configuration version {0} is newer than this version of bichrome supports")]
+    UnsupportedVersion(u64),
+    #[error("rules files don't need to be migrated")]
+    CantMigrateRules,
+    #[error("could not write configuration file")]
+    CantWriteConfiguration(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("could not load included configuration file {0:?}")]
+    InvalidInclude(PathBuf, #[source] Box<Error>),
+    #[error("configuration file {0:?} includes itself through {1:?}")]
+    IncludeCycle(PathBuf, Vec<PathBuf>),
+    #[error("could not load policy file {0:?}")]
+    InvalidPolicy(PathBuf, #[source] Box<Error>),
+    #[error("policy selector #{0} uses profile '{1}', which the policy doesn't declare")]
+    UndeclaredPolicyProfile(usize, String),
+    #[error("policy profile '{0}' falls back to '{1}', which the policy doesn't declare")]
+    UndeclaredPolicyFallback(String, String),
+    #[error("rules files can't be edited, only configuration files")]
+    CantEditRules,
+    #[error(
+        "{0:?} contains comments, which rewriting it would remove (use --force to rewrite it anyway)"
+    )]
+    WouldRemoveComments(PathBuf),
+    #[error("there is no selector with pattern '{0}'")]
+    MissingRule(String),
+    #[error("there are {1} selectors with pattern '{0}'")]
+    AmbiguousRule(String, usize),
+    #[error("position {0} is past the end of the {1} selector(s)")]
+    InvalidRulePosition(usize, usize),
+    #[error("profile '{0}' already exists")]
+    DuplicateProfile(String),
+    #[error("the edited configuration would be invalid")]
+    InvalidEdit(#[source] Box<Error>),
+    #[error("rewrite #{0} needs either a `pattern` or a `regex`, but not both")]
+    InvalidRewrite(usize),
+    #[error("rewrite #{0} turned the URL into {1:?}, which is not a valid URL")]
+    InvalidRewrittenUrl(usize, String, #[source] url::ParseError),
+    #[error("rewrites keep changing the URL: {0:?}")]
+    RewriteLoop(Vec<String>),
+    #[error("could not enable preset")]
+    InvalidPreset(#[source] presets::Error),
+    #[error("refusing to open URLs because the configuration could not be read")]
+    RefusedBrokenConfiguration(#[source] Box<Error>),
     #[error("failed to parse received url {0:?}")]
     InvalidUrlPassedIn(String, #[source] url::ParseError),
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A config value that can be specified either as a single item or as a list of items.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMany::One(item) => std::slice::from_ref(item),
+            OneOrMany::Many(items) => items,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
 pub enum ChromeProfile {
     ByName {
@@ -45,7 +142,8 @@ pub enum ChromeProfile {
         name: String,
     },
     ByHostedDomain {
-        hosted_domain: String,
+        /// One or more hosted domains, optionally with `*` wildcards, tried in order
+        hosted_domain: OneOrMany<String>,
     },
     None {},
 }
@@ -67,14 +165,17 @@ impl ChromeProfile {
                 }
             }
             ChromeProfile::ByHostedDomain { hosted_domain } => {
-                let matching_profiles = profiles.profiles_by_hosted_domain(hosted_domain);
-                if matching_profiles.is_empty() {
-                    Err(Error::InvalidHostedDomain(hosted_domain.to_owned()))
+                let hosted_domains = hosted_domain.as_slice();
+                let matching_profile = hosted_domains.iter().find_map(|hosted_domain| {
+                    profiles
+                        .profiles_by_hosted_domain(hosted_domain)
+                        .first()
+                        .copied()
+                });
+                if let Some(profile) = matching_profile {
+                    Ok(Some(format!("--profile-directory={}", profile)))
                 } else {
-                    Ok(Some(format!(
-                        "--profile-directory={}",
-                        matching_profiles[0].clone()
-                    )))
+                    Err(Error::InvalidHostedDomain(hosted_domains.to_vec()))
                 }
             }
             ChromeProfile::None {} => Ok(None),
@@ -82,7 +183,7 @@ impl ChromeProfile {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
 pub enum EdgeProfile {
     ByName {
@@ -101,8 +202,150 @@ impl EdgeProfile {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum FirefoxProfile {
+    ByName {
+        #[serde(rename = "profile")]
+        name: String,
+    },
+    ByPath {
+        #[serde(rename = "profile_path")]
+        path: PathBuf,
+    },
+    None {},
+}
+
+impl FirefoxProfile {
+    /// Get the arguments to select this profile, looking up profiles by name in `profiles.ini` in `data_path`.
+    pub fn get_arguments(&self, data_path: Option<PathBuf>) -> Result<Vec<String>> {
+        match self {
+            FirefoxProfile::ByName { name } => {
+                let data_path = data_path.ok_or(Error::CantLocateFirefoxProfiles)?;
+                let profiles =
+                    read_profiles_from_dir(data_path).map_err(Error::CantParseFirefoxProfiles)?;
+                trace!("Found Firefox profiles: {profiles:?}");
+
+                if let Some(profile) = profiles.profile_by_name(name) {
+                    Ok(vec!["-P".to_string(), profile.name.clone()])
+                } else {
+                    Err(Error::InvalidFirefoxProfileName(name.to_owned()))
+                }
+            }
+            FirefoxProfile::ByPath { path } => {
+                if path.is_dir() {
+                    Ok(vec![
+                        "--profile".to_string(),
+                        path.to_string_lossy().into_owned(),
+                    ])
+                } else {
+                    Err(Error::InvalidFirefoxProfilePath(path.clone()))
+                }
+            }
+            FirefoxProfile::None {} => Ok(Vec::new()),
+        }
+    }
+}
+
+/// The characters escaped by JavaScript's `encodeURIComponent`, which is what the container extension uses to
+/// decode the parameters.
+const URI_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'!')
+    .remove(b'~')
+    .remove(b'*')
+    .remove(b'\'')
+    .remove(b'(')
+    .remove(b')');
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct FirefoxOptions {
+    #[serde(flatten)]
+    pub profile: FirefoxProfile,
+    /// Multi-Account Container to open the URL in, which requires the "Open external links in a container"
+    /// extension to be installed in the profile.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub container: Option<String>,
+}
+
+impl FirefoxOptions {
+    /// Wrap the URL in an `ext+container:` URL if a container is configured.
+    pub fn get_url(&self, url: &str) -> String {
+        match &self.container {
+            Some(container) => format!(
+                "ext+container:name={}&url={}",
+                utf8_percent_encode(container, URI_COMPONENT),
+                utf8_percent_encode(url, URI_COMPONENT)
+            ),
+            None => url.to_string(),
+        }
+    }
+
+    pub fn get_arguments(&self, data_path: Option<PathBuf>, url: &str) -> Result<Vec<String>> {
+        let mut args = self.profile.get_arguments(data_path)?;
+        args.push(self.get_url(url));
+        Ok(args)
+    }
+}
+
+/// Browsers built on Firefox's engine that we know where to find, along with their profiles.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeckoPreset {
+    Firefox,
+    FirefoxDeveloperEdition,
+    FirefoxNightly,
+    LibreWolf,
+    Floorp,
+    TorBrowser,
+}
+
+impl GeckoPreset {
+    pub const ALL: [GeckoPreset; 6] = [
+        GeckoPreset::Firefox,
+        GeckoPreset::FirefoxDeveloperEdition,
+        GeckoPreset::FirefoxNightly,
+        GeckoPreset::LibreWolf,
+        GeckoPreset::Floorp,
+        GeckoPreset::TorBrowser,
+    ];
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum GeckoInstallation {
+    Preset {
+        variant: GeckoPreset,
+    },
+    Custom {
+        executable: PathBuf,
+        /// Directory containing the browser's `profiles.ini`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        profile_root: Option<PathBuf>,
+    },
+}
+
+impl GeckoInstallation {
+    pub fn get_data_path(&self) -> Option<PathBuf> {
+        match self {
+            GeckoInstallation::Preset { variant } => get_gecko_data_path(*variant),
+            GeckoInstallation::Custom { profile_root, .. } => profile_root.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct GeckoBrowser {
+    #[serde(flatten)]
+    pub installation: GeckoInstallation,
+    #[serde(flatten)]
+    pub options: FirefoxOptions,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ExecutablePath {
+    /// Path to the program to open URLs with
     path: PathBuf,
 }
 
@@ -112,79 +355,2793 @@ impl ExecutablePath {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(tag = "browser")]
 pub enum Browser {
     Chrome(ChromeProfile),
-    Firefox,
+    Firefox(FirefoxOptions),
+    /// A browser built on Firefox's engine, like LibreWolf
+    Gecko(GeckoBrowser),
+    /// Safari on macOS and Edge on Windows
     OsDefault,
     Edge(EdgeProfile),
     Safari,
-    Executable(ExecutablePath)
+    /// Any program, which gets the URL as its only argument
+    Executable(ExecutablePath),
+    /// Whichever program the OS has registered for the URL's scheme, like Zoom for `zoommtg:` URLs
+    SystemHandler,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Browser {
+    /// Verify that any browser profile specified can be found on this machine.
+    pub fn check(&self) -> Result<()> {
+        match self {
+            Browser::Chrome(profile) => profile.get_argument().map(|_| ()),
+            Browser::Edge(profile) => profile.get_argument().map(|_| ()),
+            Browser::Firefox(options) => options
+                .profile
+                .get_arguments(get_gecko_data_path(GeckoPreset::Firefox))
+                .map(|_| ()),
+            Browser::Gecko(gecko) => gecko
+                .options
+                .profile
+                .get_arguments(gecko.installation.get_data_path())
+                .map(|_| ()),
+            Browser::OsDefault
+            | Browser::Safari
+            | Browser::Executable(_)
+            | Browser::SystemHandler => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[schemars(transform = allow_extends_in_schema)]
+pub struct Profile {
+    #[serde(flatten)]
+    pub browser: Browser,
+    /// Profiles to try, in order, if this profile's browser or browser profile can't be used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<String>,
+    /// Overrides the top-level `strip_query_params` for URLs opened with this profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub strip_query_params: Option<StripQueryParams>,
+}
+
+/// Tracking parameters that are removed from URLs when `strip_query_params` is enabled. A trailing `*` matches any
+/// parameter starting with what comes before it.
+const TRACKING_QUERY_PARAMS: [&str; 22] = [
+    "utm_*",
+    "fbclid",
+    "gclid",
+    "gclsrc",
+    "dclid",
+    "gbraid",
+    "wbraid",
+    "msclkid",
+    "mc_cid",
+    "mc_eid",
+    "_hsenc",
+    "_hsmi",
+    "mkt_tok",
+    "igshid",
+    "yclid",
+    "twclid",
+    "ttclid",
+    "li_fat_id",
+    "oly_anon_id",
+    "oly_enc_id",
+    "vero_id",
+    "_openstat",
+];
+
+/// Whether to remove tracking parameters like `utm_source` from URLs before opening them
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(untagged)]
+pub enum StripQueryParams {
+    /// `true` removes the built-in list of tracking parameters, `false` doesn't remove any
+    Enabled(bool),
+    /// Removes the built-in list of tracking parameters, along with `remove` but except for `keep`. Both can use a
+    /// trailing `*` to match any parameter starting with what comes before it.
+    Custom {
+        #[serde(default)]
+        remove: Vec<String>,
+        #[serde(default)]
+        keep: Vec<String>,
+    },
+}
+
+impl StripQueryParams {
+    /// Whether the parameter called `name` should be removed
+    fn strips(&self, name: &str) -> bool {
+        fn matches(pattern: &str, name: &str) -> bool {
+            match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            }
+        }
+
+        let (remove, keep): (&[String], &[String]) = match self {
+            StripQueryParams::Enabled(false) => return false,
+            StripQueryParams::Enabled(true) => (&[], &[]),
+            StripQueryParams::Custom { remove, keep } => (remove, keep),
+        };
+        let tracking = TRACKING_QUERY_PARAMS
+            .iter()
+            .any(|pattern| matches(pattern, name));
+        (tracking || remove.iter().any(|pattern| matches(pattern, name)))
+            && !keep.iter().any(|pattern| matches(pattern, name))
+    }
+
+    /// Remove the parameters that should be stripped from `url`, leaving the others exactly as they were.
+    fn apply(&self, url: &mut Url) {
+        let Some(query) = url.query() else {
+            return;
+        };
+
+        let parameters: Vec<&str> = query.split('&').collect();
+        let kept: Vec<&str> = parameters
+            .iter()
+            .copied()
+            .filter(|parameter| {
+                let name = parameter.split('=').next().unwrap_or_default();
+                !self.strips(&percent_decode_str(name).decode_utf8_lossy())
+            })
+            .collect();
+        if kept.len() == parameters.len() {
+            return;
+        }
+
+        let query = (!kept.is_empty()).then(|| kept.join("&"));
+        url.set_query(query.as_deref());
+    }
+}
+
+/// A profile to try opening a URL with
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub profile_name: String,
+    pub browser: Browser,
+    /// The URL to open, with tracking parameters removed if `strip_query_params` is enabled for the profile
+    pub url: String,
+}
+
+/// Profiles that `extends` another profile or template only need to specify the fields they change, so they shouldn't
+/// be required to specify a `browser`.
+fn allow_extends_in_schema(schema: &mut Schema) {
+    let profile = schema.clone();
+    *schema = json_schema!({
+        "anyOf": [
+            profile,
+            {
+                "type": "object",
+                "properties": {
+                    "extends": {
+                        "description": "Name of a profile or template to inherit all unspecified fields from",
+                        "type": "string"
+                    }
+                },
+                "required": ["extends"]
+            }
+        ]
+    });
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ProfilePattern {
+    /// Name of the profile to open URLs matching `pattern` with
     pub profile: String,
+    /// A WebExtension match pattern, like `*.mozilla.org` or `https://github.com/MyCorp/*`
+    #[schemars(with = "String")]
+    pub pattern: Pattern,
+    /// The configuration file this selector was declared in, filled in when loading the configuration
+    #[serde(default, skip_serializing)]
+    #[schemars(skip)]
+    pub source: Option<PathBuf>,
+}
+
+/// A rule that changes URLs before they're matched against the selectors and opened
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Rewrite {
+    /// A WebExtension match pattern for the URLs to rewrite. `replacement` can use `{scheme}`, `{host}` (including
+    /// the port), `{path}`, `{query}` and `{fragment}` to refer to the parts of the URL.
+    #[schemars(with = "Option<String>")]
+    pub pattern: Option<Pattern>,
+    /// A regular expression for the URLs to rewrite. Only the part of the URL it matches is replaced, and
+    /// `replacement` can refer to its groups as `$1` or `$name`.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_regex",
+        serialize_with = "serialize_regex"
+    )]
+    #[schemars(with = "Option<String>")]
+    pub regex: Option<Regex>,
+    /// What to replace the URL (or the part of it matched by `regex`) with
+    pub replacement: String,
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Regex>, D::Error> {
+    let Some(regex) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    Regex::new(&regex)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn serialize_regex<S: Serializer>(
+    regex: &Option<Regex>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    regex.as_ref().map(Regex::as_str).serialize(serializer)
+}
+
+impl Rewrite {
+    /// Get the rewritten URL, if this rewrite applies to `url`.
+    fn apply(&self, url: &Url) -> Option<String> {
+        if let Some(regex) = &self.regex {
+            let url = url.as_str();
+            regex
+                .is_match(url)
+                .then(|| regex.replace(url, self.replacement.as_str()).into_owned())
+        } else if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(url) {
+                return None;
+            }
+
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{host}:{port}"),
+                (host, _) => host.unwrap_or_default().to_string(),
+            };
+            let query = url.query().map(|query| format!("?{query}"));
+            let fragment = url.fragment().map(|fragment| format!("#{fragment}"));
+            Some(
+                self.replacement
+                    .replace("{scheme}", url.scheme())
+                    .replace("{host}", &host)
+                    .replace("{path}", url.path())
+                    .replace("{query}", query.as_deref().unwrap_or_default())
+                    .replace("{fragment}", fragment.as_deref().unwrap_or_default()),
+            )
+        } else {
+            None
+        }
+    }
+}
+
+/// Which URL to open after a redirector's URL has been unwrapped
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LaunchUrl {
+    /// The URL the redirector redirects to, with `rewrites` applied
+    #[default]
+    Unwrapped,
+    /// The URL as it was received, so that the redirector still sees it
+    Original,
+}
+
+/// A redirector whose URLs contain the URL they redirect to in a query parameter
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Redirector {
+    /// A WebExtension match pattern for the redirector's URLs, like `*://go.mycorp.net/out*`
+    #[schemars(with = "String")]
     pub pattern: Pattern,
+    /// The query parameter holding the URL it redirects to
+    pub parameter: String,
+}
+
+/// Redirectors that are always unwrapped, and the query parameter holding the URL they redirect to
+const BUILTIN_REDIRECTORS: [(&str, &str); 10] = [
+    ("*://*.safelinks.protection.outlook.com/*", "url"),
+    (
+        "*://statics.teams.cdn.office.net/evergreen-assets/safelinks/*",
+        "url",
+    ),
+    ("*://www.google.com/url?*", "q"),
+    ("*://www.google.com/url?*", "url"),
+    ("*://slack-redir.net/link?*", "url"),
+    ("*://l.facebook.com/l.php?*", "u"),
+    ("*://l.messenger.com/l.php?*", "u"),
+    ("*://l.instagram.com/?*", "u"),
+    ("*://www.youtube.com/redirect?*", "q"),
+    ("*://www.linkedin.com/redir/redirect?*", "url"),
+];
+
+/// The patterns of `BUILTIN_REDIRECTORS`, compiled once
+static BUILTIN_REDIRECTOR_PATTERNS: LazyLock<Vec<(Pattern, &str)>> = LazyLock::new(|| {
+    BUILTIN_REDIRECTORS
+        .iter()
+        .filter_map(|(pattern, parameter)| {
+            Pattern::new(pattern, true)
+                .ok()
+                .map(|pattern| (pattern, *parameter))
+        })
+        .collect()
+});
+
+/// Unwrapping of URLs from redirectors and "safe link" services, so that URLs are matched against the selectors using
+/// the URL they redirect to
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct UnwrapRedirects {
+    #[serde(default)]
+    pub launch: LaunchUrl,
+    /// Redirectors to unwrap in addition to the built-in ones, which are checked before them
+    #[serde(default)]
+    pub redirectors: Vec<Redirector>,
+}
+
+impl UnwrapRedirects {
+    /// Get the URL that `url` redirects to, if it's from one of the redirectors.
+    fn unwrap(&self, url: &Url) -> Option<Url> {
+        let builtin = BUILTIN_REDIRECTOR_PATTERNS
+            .iter()
+            .map(|(pattern, parameter)| (pattern, *parameter));
+        let custom = self
+            .redirectors
+            .iter()
+            .map(|redirector| (&redirector.pattern, redirector.parameter.as_str()));
+
+        custom
+            .chain(builtin)
+            .filter(|(pattern, _)| pattern.is_match(url))
+            .find_map(|(_, parameter)| {
+                url.query_pairs()
+                    .find(|(key, _)| *key == parameter)
+                    .and_then(|(_, value)| Url::parse(&value).ok())
+            })
+    }
+}
+
+/// A change made to a URL before it's matched against the selectors
+#[derive(Debug, Clone)]
+pub enum RewriteStep {
+    /// The URL was from a redirector, and was replaced with the URL it redirects to
+    Unwrapped { url: String },
+    /// One of the `rewrites`, at position `rewrite`, changed the URL
+    Rewritten { rewrite: usize, url: String },
+    /// A preset converted the URL into one that opens a desktop app
+    Preset { preset: &'static str, url: String },
+}
+
+impl RewriteStep {
+    pub fn url(&self) -> &str {
+        match self {
+            RewriteStep::Unwrapped { url }
+            | RewriteStep::Rewritten { url, .. }
+            | RewriteStep::Preset { url, .. } => url,
+        }
+    }
 }
 
+/// A URL with redirectors unwrapped and `rewrites` applied
+#[derive(Debug, Clone)]
+pub struct PreparedUrl {
+    /// Each change that was made to the URL
+    pub steps: Vec<RewriteStep>,
+    /// The URL after unwrapping redirectors, but before `rewrites` and presets, which the policy is checked against
+    pub unwrapped_url: String,
+    /// The URL to match against the selectors
+    pub url: String,
+    /// The URL to open
+    pub launch_url: String,
+}
+
+/// How many times a URL can be changed before we assume the rewrites are stuck in a loop
+const MAX_REWRITES: usize = 16;
+
+/// Why a particular profile was picked for an URL
+#[derive(Debug, Clone)]
+pub enum Selection<'a> {
+    /// The URL matched the selector at the given index of `profile_selection`
+    Selector(usize, &'a ProfilePattern),
+    /// No selector matched, so the `default_profile` was used
+    DefaultProfile(&'a str),
+    /// The URL was converted by the named preset, so it's opened by the app registered for its scheme
+    Preset(&'static str),
+    /// No selector matched and there's no `default_profile`, so Chrome without a profile was used
+    ImplicitDefault,
+    /// No selector matched, and the policy forbids the `default_profile` or has forbidden profiles for the URL, so it
+    /// isn't opened at all rather than in whichever profile Chrome picks
+    Forbidden,
+}
+
+/// Where the selectors of included configuration files go, relative to the selectors of the file including them
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IncludedRules {
+    Before,
+    #[default]
+    After,
+}
+
+/// What to do when the configuration can't be read. This comes from the last configuration that could be read, since
+/// the current one is broken.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnConfigError {
+    /// Keep using the last configuration that could be read
+    #[default]
+    LastGood,
+    /// Open URLs using only the system-wide policy, or in Chrome without a profile if there's none
+    DefaultOnly,
+    /// Don't open URLs at all until the configuration is fixed
+    Refuse,
+}
+
+/// The last configuration that could be read, which is used when the configuration is broken
+#[derive(Serialize, Deserialize)]
+struct CachedConfiguration {
+    /// The configuration file this was read from, so we don't use it for a different one
+    path: PathBuf,
+    /// The configuration with includes and the rules file merged in, before overrides and the policy are applied
+    configuration: Value,
+}
+
+/// A policy rule that prevents URLs matching `pattern` from being opened with any of `profiles`
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForbiddenProfiles {
+    pub pattern: Pattern,
+    pub profiles: Vec<String>,
+}
+
+/// The system-wide policy file, which is managed by an administrator and can't be overridden by the user's
+/// configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Policy {
+    /// Profiles that replace the user's profiles with the same name
+    #[serde(default)]
+    profiles: Map<String, Value>,
+    #[serde(default)]
+    templates: Map<String, Value>,
+    /// Selectors that are checked before the user's selectors
+    #[serde(default)]
+    profile_selection: Vec<Value>,
+    #[serde(default)]
+    forbid: Vec<ForbiddenProfiles>,
+}
+
+impl Policy {
+    fn read_from_file(path: &Path) -> Result<Policy> {
+        let mut policy = read_configuration_value(path, &mut Vec::new())?;
+        // Resolve `extends` using only the policy's own declarations, so that the user can't change pinned profiles
+        // through their templates.
+        resolve_extends(&mut policy)?;
+        let policy: Policy = serde_json::from_value(policy).map_err(Error::InvalidJson)?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Make sure the policy's selectors and the fallbacks of its profiles only use profiles the policy declares, since
+    /// the user could otherwise declare them as anything.
+    fn validate(&self) -> Result<()> {
+        for (index, selector) in self.profile_selection.iter().enumerate() {
+            let profile_name = selector
+                .get("profile")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if !self.profiles.contains_key(profile_name) {
+                return Err(Error::UndeclaredPolicyProfile(
+                    index,
+                    profile_name.to_string(),
+                ));
+            }
+        }
+
+        for (profile_name, profile) in &self.profiles {
+            let fallbacks = profile
+                .get("fallback")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten();
+            for fallback in fallbacks {
+                let fallback = fallback.as_str().unwrap_or_default();
+                if !self.profiles.contains_key(fallback) {
+                    return Err(Error::UndeclaredPolicyFallback(
+                        profile_name.clone(),
+                        fallback.to_string(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pin the policy's profiles and put its selectors ahead of the ones in `configuration`.
+    fn apply(&self, configuration: &mut Value) {
+        let Some(configuration) = configuration.as_object_mut() else {
+            return;
+        };
+
+        if let Value::Object(profiles) = configuration
+            .entry("profiles")
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            profiles.extend(self.profiles.clone());
+        }
+
+        let mut profile_selection = self.profile_selection.clone();
+        if let Some(Value::Array(existing)) = configuration.get_mut("profile_selection") {
+            profile_selection.append(existing);
+        }
+        configuration.insert(
+            "profile_selection".to_string(),
+            Value::Array(profile_selection),
+        );
+    }
+}
+
+/// The file names we look for the configuration under, in order of preference
+const CONFIG_FILE_NAMES: [&str; 4] = [
+    "bichrome_config.json",
+    "bichrome_config.toml",
+    "bichrome_config.yaml",
+    "bichrome_config.yml",
+];
+
+/// Find the configuration file in `directory`, which can be in any of the supported formats. Returns the path of the
+/// JSON configuration if there's none.
+pub fn find_config_file(directory: &Path) -> PathBuf {
+    CONFIG_FILE_NAMES
+        .iter()
+        .map(|file_name| directory.join(file_name))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| directory.join(CONFIG_FILE_NAMES[0]))
+}
+
+/// The environment variable that can be set to the path of the configuration file to use
+pub const CONFIG_PATH_VARIABLE: &str = "BICHROME_CONFIG";
+
+/// How we decided which configuration file to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// The path was passed on the command line
+    Argument,
+    /// The path came from `BICHROME_CONFIG`
+    Environment,
+    /// The file was found by looking through the search path
+    SearchPath,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConfigSource::Argument => "from --config",
+            ConfigSource::Environment => "from BICHROME_CONFIG",
+            ConfigSource::SearchPath => "from the search path",
+        })
+    }
+}
+
+/// The `bichrome` directory in the XDG config directory, `$XDG_CONFIG_HOME` or `~/.config`
+pub fn get_xdg_config_path() -> Option<PathBuf> {
+    let non_empty = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+    non_empty("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| non_empty("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|path| path.join("bichrome"))
+}
+
+/// Decide which configuration file to use: `config_override` if it's set, then `BICHROME_CONFIG`, then the first
+/// directory in `search_path` that has a configuration file or a rules file. If none of them do, this returns the path of the JSON
+/// configuration in the first directory, so there's somewhere to create one. Only returns `None` for an empty
+/// `search_path`.
+pub fn locate_config_file(
+    config_override: Option<&Path>,
+    search_path: &[PathBuf],
+) -> Option<(PathBuf, ConfigSource)> {
+    if let Some(path) = config_override {
+        return Some((path.to_path_buf(), ConfigSource::Argument));
+    }
+    if let Some(path) = std::env::var_os(CONFIG_PATH_VARIABLE).filter(|path| !path.is_empty()) {
+        return Some((PathBuf::from(path), ConfigSource::Environment));
+    }
+
+    search_path
+        .iter()
+        .map(|directory| find_config_file(directory))
+        .find(|path| path.is_file() || path.with_file_name(RULES_FILE_NAME).is_file())
+        .or_else(|| {
+            search_path
+                .first()
+                .map(|directory| find_config_file(directory))
+        })
+        .map(|path| (path, ConfigSource::SearchPath))
+}
+
+/// Read the configuration from `path` and the rules file next to it, with includes resolved. The rules file can also be
+/// used without a configuration file.
+fn read_merged_configuration(path: &Path) -> Result<Value> {
+    let has_rules = path.with_file_name(RULES_FILE_NAME).is_file();
+    let declarations = if !has_rules || path.is_file() {
+        Some(read_configuration_value(path, &mut Vec::new())?)
+    } else {
+        None
+    };
+    merge_rules_file(path, declarations)
+}
+
+/// Merge the rules file next to `path`, if there is one, with the `declarations` read from `path`.
+fn merge_rules_file(path: &Path, declarations: Option<Value>) -> Result<Value> {
+    let rules_path = path.with_file_name(RULES_FILE_NAME);
+    let mut configuration = Map::new();
+    if rules_path.is_file() {
+        let rules = read_configuration_value(&rules_path, &mut Vec::new())?;
+        merge_configuration(&mut configuration, rules, false);
+    }
+    if let Some(declarations) = declarations {
+        merge_configuration(&mut configuration, declarations, true);
+    }
+
+    Ok(Value::Object(configuration))
+}
+
+/// Remember `configuration`, read from `path`, as the last one that could be read. Does nothing if it hasn't changed.
+fn write_cached_configuration(cache_path: &Path, path: &Path, configuration: Value) -> Result<()> {
+    let cached = CachedConfiguration {
+        path: path.to_path_buf(),
+        configuration,
+    };
+    let contents = serde_json::to_string_pretty(&cached)
+        .map_err(|e| Error::CantWriteConfiguration(Box::new(e)))?;
+    if std::fs::read_to_string(cache_path).is_ok_and(|existing| existing == contents) {
+        return Ok(());
+    }
+
+    if let Some(directory) = cache_path.parent() {
+        std::fs::create_dir_all(directory)
+            .map_err(|e| Error::CantWriteConfiguration(Box::new(e)))?;
+    }
+    std::fs::write(cache_path, contents).map_err(|e| Error::CantWriteConfiguration(Box::new(e)))
+}
+
+/// Load the last configuration that could be read from `path`, if there is one.
+fn read_cached_configuration(cache_path: &Path, path: &Path) -> Result<Option<Configuration>> {
+    let contents = std::fs::read_to_string(cache_path).map_err(Error::InvalidFile)?;
+    let cached: CachedConfiguration =
+        serde_json::from_str(&contents).map_err(Error::InvalidJson)?;
+    if cached.path != path {
+        trace!(
+            "ignoring last good config, it was read from {}",
+            cached.path.display()
+        );
+        return Ok(None);
+    }
+
+    Configuration::load(cached.configuration).map(Some)
+}
+
+/// The current version of the configuration format
+pub const CONFIG_VERSION: u64 = 1;
+
+fn current_version() -> u64 {
+    CONFIG_VERSION
+}
+
+/// The name of the optional rules file that lives next to the configuration file
+const RULES_FILE_NAME: &str = "bichrome.rules";
+
+/// Browsers that a rules file can refer to by name without declaring a profile for them
+const IMPLICIT_BROWSER_PROFILES: [&str; 5] = ["Chrome", "Edge", "Firefox", "OsDefault", "Safari"];
+
+/// The name we use to refer to the Chrome we fall back to when there's no `default_profile`
+const IMPLICIT_DEFAULT_PROFILE: &str = "(no default_profile)";
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Configuration {
+    /// Version of the configuration format, older versions are migrated when loading them
+    #[serde(default = "current_version")]
+    pub version: u64,
+    /// Profile to use for URLs that don't match any selector
     pub default_profile: Option<String>,
-    pub profiles: HashMap<String, Browser>,
+    pub profiles: HashMap<String, Profile>,
+    /// Selectors that pick the profile for an URL, the first one that matches is used
     pub profile_selection: Vec<ProfilePattern>,
+    /// Rules that change URLs before they're matched against the selectors, applied in order
+    #[serde(default)]
+    pub rewrites: Vec<Rewrite>,
+    /// Match URLs from redirectors like Outlook's safe links using the URL they redirect to
+    pub unwrap_redirects: Option<UnwrapRedirects>,
+    /// Remove tracking parameters like `utm_source` from URLs before opening them
+    pub strip_query_params: Option<StripQueryParams>,
+    /// Built-in presets that open links to apps like Zoom in the app, as `name` or `name@version`
+    #[serde(default)]
+    pub presets: Vec<String>,
+    #[serde(default)]
+    pub on_config_error: OnConfigError,
+    /// Profiles that the policy file doesn't allow certain URLs to be opened with
+    #[serde(skip)]
+    pub forbidden: Vec<ForbiddenProfiles>,
+    /// How many of the selectors at the start of `profile_selection` come from the policy file
+    #[serde(skip)]
+    policy_selectors: usize,
+    /// The presets named in `presets`
+    #[serde(skip)]
+    enabled_presets: Vec<&'static Preset>,
+}
+
+/// A configuration file as written by the user, before includes, overrides and `extends` are resolved. This is only
+/// used to generate the JSON schema for configuration files.
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[schemars(title = "bichrome configuration")]
+struct ConfigurationFile {
+    /// The JSON schema for the configuration, to get validation and autocomplete in editors
+    #[serde(rename = "$schema")]
+    schema: Option<String>,
+    #[serde(flatten)]
+    configuration: Configuration,
+    /// Other configuration files to merge into this one, relative to this file
+    include: Option<OneOrMany<PathBuf>>,
+    included_rules: Option<IncludedRules>,
+    /// Partial profiles that can only be used through `extends`
+    #[serde(default)]
+    templates: Map<String, Value>,
+    /// Changes to the configuration that only apply on some machines
+    #[serde(default)]
+    overrides: Vec<Override>,
+}
+
+/// Generate a JSON schema describing configuration files.
+pub fn json_schema() -> Schema {
+    schemars::schema_for!(ConfigurationFile)
 }
 
 impl Configuration {
     pub fn empty() -> Configuration {
         Configuration {
+            version: CONFIG_VERSION,
             default_profile: None,
             profiles: HashMap::new(),
             profile_selection: Vec::new(),
+            rewrites: Vec::new(),
+            unwrap_redirects: None,
+            strip_query_params: None,
+            presets: Vec::new(),
+            on_config_error: OnConfigError::default(),
+            forbidden: Vec::new(),
+            policy_selectors: 0,
+            enabled_presets: Vec::new(),
         }
     }
 
+    /// Read the configuration from `path`, along with the rules file next to it and the system-wide policy file if
+    /// there are any. The rules file can also be used without a configuration file.
     pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Configuration> {
-        let file = File::open(path).map_err(Error::InvalidFile)?;
-        let reader = BufReader::new(file);
-        let configuration = serde_json::from_reader(reader).map_err(Error::InvalidJson)?;
-        Ok(configuration)
+        Self::load(read_merged_configuration(path.as_ref())?)
     }
 
-    fn get_profile(&self, profile_name: &str) -> Result<&Browser> {
-        for (profile, browser) in &self.profiles {
-            if profile == profile_name {
-                return Ok(browser);
+    /// Read the configuration from `path` like `read_from_file`, but remember the last configuration that could be
+    /// read in `cache_path`, and use it according to `on_config_error` when the configuration is broken. Only fails if
+    /// `on_config_error` is `Refuse`, or if the policy can't be read.
+    pub fn read_with_fallback(path: &Path, cache_path: Option<&Path>) -> Result<Configuration> {
+        // Without a configuration there's nothing to fall back from, so don't resurrect one that has been deleted.
+        if !path.is_file() && !path.with_file_name(RULES_FILE_NAME).is_file() {
+            warn!(
+                "no config at {}, opening URLs without profile",
+                path.display()
+            );
+            return Self::default_only();
+        }
+
+        let error = match read_merged_configuration(path)
+            .and_then(|configuration| Ok((Self::load(configuration.clone())?, configuration)))
+        {
+            Ok((loaded, configuration)) => {
+                if let Some(cache_path) = cache_path {
+                    if let Err(e) = write_cached_configuration(cache_path, path, configuration) {
+                        warn!("failed to save config to {}: {:?}", cache_path.display(), e);
+                    }
+                }
+                return Ok(loaded);
+            }
+            Err(e) => e,
+        };
+        error!("failed to parse config: {:?}", error);
+
+        let last_good = cache_path
+            .filter(|cache_path| cache_path.is_file())
+            .and_then(
+                |cache_path| match read_cached_configuration(cache_path, path) {
+                    Ok(last_good) => last_good,
+                    Err(e) => {
+                        error!(
+                            "failed to read last good config from {}: {:?}",
+                            cache_path.display(),
+                            e
+                        );
+                        None
+                    }
+                },
+            );
+        let on_config_error = last_good
+            .as_ref()
+            .map(|last_good| last_good.on_config_error)
+            .unwrap_or_default();
+        match (on_config_error, last_good) {
+            (OnConfigError::LastGood, Some(last_good)) => {
+                warn!("using the last config that could be read instead");
+                Ok(last_good)
+            }
+            (OnConfigError::Refuse, _) => Err(Error::RefusedBrokenConfiguration(Box::new(error))),
+            _ => {
+                warn!("opening URLs without profile");
+                Self::default_only()
             }
         }
+    }
 
-        Err(Error::MissingProfile(profile_name.to_string()))
+    /// Get a configuration with only the system-wide policy file, for when the user's configuration can't be read.
+    pub fn read_policy() -> Result<Configuration> {
+        Self::load(Value::Object(Map::new()))
+    }
+
+    /// Like `read_policy`, but refuses to open URLs if the policy can't be read either, since ignoring it would let
+    /// the user open URLs with the profiles it forbids.
+    pub fn default_only() -> Result<Configuration> {
+        Self::read_policy().map_err(|e| Error::RefusedBrokenConfiguration(Box::new(e)))
+    }
+
+    fn load(configuration: Value) -> Result<Configuration> {
+        let policy = match get_policy_path() {
+            Some(policy_path) if policy_path.is_file() => {
+                trace!("applying policy from {}", policy_path.display());
+                Policy::read_from_file(&policy_path)
+                    .map_err(|err| Error::InvalidPolicy(policy_path, Box::new(err)))?
+            }
+            _ => Policy::default(),
+        };
+        Self::load_with_policy(configuration, policy)
+    }
+
+    fn load_with_policy(mut configuration: Value, policy: Policy) -> Result<Configuration> {
+        apply_overrides(&mut configuration)?;
+        resolve_extends(&mut configuration)?;
+        policy.apply(&mut configuration);
+
+        let mut configuration: Configuration =
+            serde_json::from_value(configuration).map_err(Error::InvalidJson)?;
+        configuration.forbidden = policy.forbid;
+        configuration.policy_selectors = policy.profile_selection.len();
+        configuration.validate_fallbacks()?;
+        for (index, rewrite) in configuration.rewrites.iter().enumerate() {
+            if rewrite.pattern.is_some() == rewrite.regex.is_some() {
+                return Err(Error::InvalidRewrite(index));
+            }
+        }
+        configuration.enabled_presets = configuration
+            .presets
+            .iter()
+            .map(|name| find_preset(name).map_err(Error::InvalidPreset))
+            .collect::<Result<_>>()?;
+        Ok(configuration)
     }
 
-    /// Find the best matching browser profile for the given URL.
-    pub fn choose_browser(&self, url: &str) -> Result<Browser> {
-        let url = Url::parse(url).map_err(|err| Error::InvalidUrlPassedIn(url.to_string(), err))?;
+    /// Unwrap `url` for as long as it's from a redirector, then apply the `rewrites`, starting over from the first one
+    /// after every change until none of them change it any more, and finally convert it with the first preset that
+    /// applies.
+    pub fn prepare_url(&self, url: &str) -> Result<PreparedUrl> {
+        let mut steps: Vec<RewriteStep> = Vec::new();
+        if self.rewrites.is_empty()
+            && self.unwrap_redirects.is_none()
+            && self.enabled_presets.is_empty()
+        {
+            return Ok(PreparedUrl {
+                steps,
+                unwrapped_url: url.to_string(),
+                url: url.to_string(),
+                launch_url: url.to_string(),
+            });
+        }
 
-        for profile_selector in &self.profile_selection {
-            if profile_selector.pattern.is_match(&url) {
-                return self
-                    .get_profile(&profile_selector.profile)
-                    .map(|b| b.clone());
+        let mut parsed =
+            Url::parse(url).map_err(|err| Error::InvalidUrlPassedIn(url.to_string(), err))?;
+        let received = parsed.clone();
+        if let Some(unwrap_redirects) = &self.unwrap_redirects {
+            // The policy pins the URL it matches, not the one a redirector sends on to.
+            while !self.is_pinned_by_policy(&parsed) {
+                let Some(unwrapped) = unwrap_redirects.unwrap(&parsed) else {
+                    break;
+                };
+                if steps.len() >= MAX_REWRITES {
+                    let urls = steps.iter().map(|step| step.url().to_string()).collect();
+                    return Err(Error::RewriteLoop(urls));
+                }
+                trace!("unwrapped {} to {}", parsed, unwrapped);
+                steps.push(RewriteStep::Unwrapped {
+                    url: unwrapped.to_string(),
+                });
+                parsed = unwrapped;
             }
         }
 
-        // If there's a default_profile, use that, otherwise default to a Chrome without profiles.
-        if let Some(default_profile) = &self.default_profile {
-            self.get_profile(default_profile).map(|b| b.clone())
+        let unwrapped_url = steps.last().map_or(url, RewriteStep::url).to_string();
+        // Rewrites and presets could otherwise move a URL off the profile the policy pins it to.
+        if self.is_pinned_by_policy(&parsed) {
+            trace!(
+                "not rewriting {} as the policy pins it to a profile",
+                parsed
+            );
         } else {
-            Ok(Browser::Chrome(ChromeProfile::None {}))
+            let unwrapped = parsed.clone();
+            loop {
+                // Compare the parsed URLs, so that a rewrite that only changes how the URL is written, like dropping
+                // the trailing `/` of an empty path, doesn't count as a change.
+                let mut rewritten = None;
+                for (index, rewrite) in self.rewrites.iter().enumerate() {
+                    let Some(replacement) = rewrite.apply(&parsed) else {
+                        continue;
+                    };
+                    let replacement = Url::parse(&replacement)
+                        .map_err(|err| Error::InvalidRewrittenUrl(index, replacement, err))?;
+                    if replacement != parsed {
+                        rewritten = Some((index, replacement));
+                        break;
+                    }
+                }
+                let Some((index, rewritten)) = rewritten else {
+                    break;
+                };
+                trace!("rewrite #{} changed the URL to {}", index, rewritten);
+
+                let seen =
+                    std::iter::once(received.as_str()).chain(steps.iter().map(RewriteStep::url));
+                if steps.len() >= MAX_REWRITES
+                    || seen.clone().any(|seen| seen == rewritten.as_str())
+                {
+                    let mut urls: Vec<String> = seen.map(str::to_string).collect();
+                    urls.push(rewritten.to_string());
+                    return Err(Error::RewriteLoop(urls));
+                }
+
+                steps.push(RewriteStep::Rewritten {
+                    rewrite: index,
+                    url: rewritten.to_string(),
+                });
+                parsed = rewritten;
+            }
+
+            // The policy can forbid a preset like a profile, by its name.
+            let policy_urls = [unwrapped, parsed];
+            let converted = self
+                .enabled_presets
+                .iter()
+                .filter(|preset| !self.is_forbidden(&policy_urls, preset.name))
+                .find_map(|preset| {
+                    preset
+                        .convert(policy_urls[1].as_str())
+                        .map(|converted| (preset.name, converted))
+                });
+            if let Some((preset, converted)) = converted {
+                trace!("preset '{}' changed the URL to {}", preset, converted);
+                steps.push(RewriteStep::Preset {
+                    preset,
+                    url: converted,
+                });
+            }
         }
+
+        let prepared_url = steps.last().map_or(url, RewriteStep::url).to_string();
+        // The app a preset converted the URL for can't open the original one.
+        let launch_original = !matches!(steps.last(), Some(RewriteStep::Preset { .. }))
+            && self
+                .unwrap_redirects
+                .as_ref()
+                .is_some_and(|unwrap_redirects| unwrap_redirects.launch == LaunchUrl::Original);
+        Ok(PreparedUrl {
+            launch_url: if launch_original {
+                url.to_string()
+            } else {
+                prepared_url.clone()
+            },
+            unwrapped_url,
+            url: prepared_url,
+            steps,
+        })
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Template {
-    profiles: HashMap<String, String>,
-    configuration: Configuration,
+    /// Whether one of the policy's selectors matches `url` with a profile that isn't forbidden for it
+    fn is_pinned_by_policy(&self, url: &Url) -> bool {
+        self.profile_selection[..self.policy_selectors]
+            .iter()
+            .any(|selector| {
+                selector.pattern.is_match(url)
+                    && !self.is_forbidden(std::slice::from_ref(url), &selector.profile)
+            })
+    }
+
+    /// Parse the URLs of `url` that the policy's forbidden profiles are checked against: the URL before and after
+    /// `rewrites` and presets changed it, and the one that's launched.
+    fn policy_urls(url: &PreparedUrl) -> Result<[Url; 3]> {
+        let parse =
+            |u: &str| Url::parse(u).map_err(|err| Error::InvalidUrlPassedIn(u.to_string(), err));
+        Ok([
+            parse(&url.unwrapped_url)?,
+            parse(&url.url)?,
+            parse(&url.launch_url)?,
+        ])
+    }
+
+    /// Whether the policy forbids opening any of `urls` with the given profile
+    fn is_forbidden(&self, urls: &[Url], profile_name: &str) -> bool {
+        self.forbidden.iter().any(|forbidden| {
+            urls.iter().any(|url| forbidden.pattern.is_match(url))
+                && forbidden.profiles.iter().any(|p| p == profile_name)
+        })
+    }
+
+    fn get_profile(&self, profile_name: &str) -> Result<&Profile> {
+        for (profile_key, profile) in &self.profiles {
+            if profile_key == profile_name {
+                return Ok(profile);
+            }
+        }
+
+        Err(Error::MissingProfile(profile_name.to_string()))
+    }
+
+    /// Make sure that every fallback refers to a declared profile, and that no profile ends up falling back to itself.
+    fn validate_fallbacks(&self) -> Result<()> {
+        fn visit<'a>(
+            config: &'a Configuration,
+            profile_name: &'a str,
+            path: &mut Vec<&'a str>,
+            visited: &mut HashSet<&'a str>,
+        ) -> Result<()> {
+            if let Some(index) = path.iter().position(|p| *p == profile_name) {
+                let mut cycle: Vec<String> = path[index..].iter().map(|p| p.to_string()).collect();
+                cycle.push(profile_name.to_string());
+                return Err(Error::FallbackCycle(profile_name.to_string(), cycle));
+            }
+
+            if !visited.insert(profile_name) {
+                return Ok(());
+            }
+
+            path.push(profile_name);
+            for fallback in &config.get_profile(profile_name)?.fallback {
+                visit(config, fallback, path, visited)?;
+            }
+            path.pop();
+
+            Ok(())
+        }
+
+        let mut visited = HashSet::new();
+        for profile_name in self.profiles.keys() {
+            visit(self, profile_name, &mut Vec::new(), &mut visited)?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the given profile followed by all of its fallbacks (and their fallbacks), in the order they should be tried.
+    fn get_profile_with_fallbacks(&self, profile_name: &str) -> Result<Vec<(String, Browser)>> {
+        fn visit(
+            config: &Configuration,
+            profile_name: &str,
+            candidates: &mut Vec<(String, Browser)>,
+        ) -> Result<()> {
+            if candidates.iter().any(|(name, _)| name == profile_name) {
+                return Ok(());
+            }
+
+            let profile = config.get_profile(profile_name)?;
+            candidates.push((profile_name.to_string(), profile.browser.clone()));
+            for fallback in &profile.fallback {
+                visit(config, fallback, candidates)?;
+            }
+
+            Ok(())
+        }
+
+        let mut candidates = Vec::new();
+        visit(self, profile_name, &mut candidates)?;
+        Ok(candidates)
+    }
+
+    /// Look for problems in the configuration, such as selectors referring to undeclared profiles
+    /// or browser profiles that can't be found on this machine.
+    pub fn check(&self) -> Vec<Error> {
+        let mut errors = Vec::new();
+
+        let mut referenced_profiles = HashSet::new();
+        let profile_references = self
+            .default_profile
+            .iter()
+            .chain(self.profile_selection.iter().map(|s| &s.profile));
+        for profile_name in profile_references {
+            if !referenced_profiles.insert(profile_name) {
+                continue;
+            }
+
+            if let Err(error) = self.get_profile(profile_name) {
+                errors.push(error);
+            }
+        }
+
+        for (profile_name, profile) in &self.profiles {
+            if let Err(error) = profile.browser.check() {
+                errors.push(Error::InvalidProfile(profile_name.clone(), Box::new(error)));
+            }
+        }
+
+        errors
+    }
+
+    /// Figure out which selector (if any) picks the profile for the given URL.
+    pub fn select_profile(&self, url: &PreparedUrl) -> Result<Selection<'_>> {
+        let policy_urls = Self::policy_urls(url)?;
+        let url = &policy_urls[1];
+
+        if let Some(preset) = self.enabled_presets.iter().find(|preset| {
+            preset.scheme == url.scheme() && !self.is_forbidden(&policy_urls, preset.name)
+        }) {
+            return Ok(Selection::Preset(preset.name));
+        }
+
+        for (index, profile_selector) in self.profile_selection.iter().enumerate() {
+            if profile_selector.pattern.is_match(url) {
+                if self.is_forbidden(&policy_urls, &profile_selector.profile) {
+                    trace!(
+                        "skipping selector #{} as policy forbids profile '{}'",
+                        index,
+                        profile_selector.profile
+                    );
+                    continue;
+                }
+                return Ok(Selection::Selector(index, profile_selector));
+            }
+        }
+
+        Ok(match &self.default_profile {
+            Some(default_profile) if !self.is_forbidden(&policy_urls, default_profile) => {
+                Selection::DefaultProfile(default_profile)
+            }
+            // Chrome without a profile opens the URL in the profile that was used last, which may well be forbidden.
+            Some(_) => Selection::Forbidden,
+            None if self.forbidden.iter().any(|forbidden| {
+                policy_urls
+                    .iter()
+                    .any(|url| forbidden.pattern.is_match(url))
+            }) =>
+            {
+                Selection::Forbidden
+            }
+            None => Selection::ImplicitDefault,
+        })
+    }
+
+    /// Find the best matching browser profile for the given URL, followed by its fallbacks, in the order they should be
+    /// tried.
+    pub fn choose_browser(&self, url: &PreparedUrl) -> Result<Vec<Candidate>> {
+        let mut candidates = match self.select_profile(url)? {
+            Selection::Selector(_, profile_selector) => {
+                self.get_profile_with_fallbacks(&profile_selector.profile)?
+            }
+            Selection::DefaultProfile(default_profile) => {
+                self.get_profile_with_fallbacks(default_profile)?
+            }
+            // If there's no default_profile, default to a Chrome without profiles.
+            Selection::ImplicitDefault => vec![(
+                IMPLICIT_DEFAULT_PROFILE.to_string(),
+                Browser::Chrome(ChromeProfile::None {}),
+            )],
+            Selection::Preset(preset) => vec![(preset.to_string(), Browser::SystemHandler)],
+            Selection::Forbidden => Vec::new(),
+        };
+
+        // Don't fall back to profiles that the policy forbids for this URL either.
+        if !self.forbidden.is_empty() {
+            let policy_urls = Self::policy_urls(url)?;
+            candidates.retain(|(profile_name, _)| !self.is_forbidden(&policy_urls, profile_name));
+        }
+
+        let launch_url = &url.launch_url;
+        candidates
+            .into_iter()
+            .map(|(profile_name, browser)| {
+                let strip_query_params = self
+                    .profiles
+                    .get(&profile_name)
+                    .and_then(|profile| profile.strip_query_params.as_ref())
+                    .or(self.strip_query_params.as_ref())
+                    .filter(|strip_query_params| {
+                        !matches!(strip_query_params, StripQueryParams::Enabled(false))
+                    });
+                // Only parse the URL when we have to, so that it's otherwise opened exactly as it was received.
+                let url = match strip_query_params {
+                    Some(strip_query_params) => {
+                        let mut url = Url::parse(launch_url).map_err(|err| {
+                            Error::InvalidUrlPassedIn(launch_url.to_string(), err)
+                        })?;
+                        strip_query_params.apply(&mut url);
+                        url.into()
+                    }
+                    None => launch_url.to_string(),
+                };
+                Ok(Candidate {
+                    profile_name,
+                    browser,
+                    url,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Read a configuration file along with all the files it includes, merged into a single configuration. Every
+/// selector is tagged with the file it was declared in. `include_stack` holds the files currently being read, so
+/// that we can detect files that (indirectly) include themselves.
+fn read_configuration_value(path: &Path, include_stack: &mut Vec<PathBuf>) -> Result<Value> {
+    let contents = std::fs::read_to_string(path).map_err(Error::InvalidFile)?;
+    let configuration = parse_configuration(&contents, path)?;
+    resolve_configuration_value(path, configuration, include_stack)
+}
+
+/// Migrate, tag and resolve the includes of `configuration`, which was parsed from `path`.
+fn resolve_configuration_value(
+    path: &Path,
+    mut configuration: Value,
+    include_stack: &mut Vec<PathBuf>,
+) -> Result<Value> {
+    let canonical_path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if let Some(index) = include_stack.iter().position(|p| *p == canonical_path) {
+        let mut cycle = include_stack[index..].to_vec();
+        cycle.push(canonical_path);
+        return Err(Error::IncludeCycle(path.to_path_buf(), cycle));
+    }
+
+    if let Some(version) = migrate(&mut configuration)? {
+        trace!("migrated {} from version {}", path.display(), version);
+    }
+    tag_selector_sources(&mut configuration, path);
+
+    let Some(declarations) = configuration.as_object_mut() else {
+        return Ok(configuration);
+    };
+    let Some(include) = declarations.remove("include") else {
+        return Ok(configuration);
+    };
+    let include: OneOrMany<PathBuf> =
+        serde_json::from_value(include).map_err(Error::InvalidJson)?;
+    let included_rules: IncludedRules = match declarations.remove("included_rules") {
+        Some(included_rules) => {
+            serde_json::from_value(included_rules).map_err(Error::InvalidJson)?
+        }
+        None => IncludedRules::default(),
+    };
+
+    include_stack.push(canonical_path);
+    // Included paths are relative to the file including them.
+    let base_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut merged = Map::new();
+    for included_path in include.as_slice() {
+        let included_path = base_path.join(included_path);
+        trace!("including configuration from {}", included_path.display());
+        let included = read_configuration_value(&included_path, include_stack)
+            .map_err(|err| Error::InvalidInclude(included_path, Box::new(err)))?;
+        merge_configuration(&mut merged, included, false);
+    }
+    include_stack.pop();
+
+    merge_configuration(
+        &mut merged,
+        configuration,
+        included_rules == IncludedRules::After,
+    );
+    Ok(Value::Object(merged))
+}
+
+/// Parse the contents of a configuration file into a JSON value, using the format implied by the file extension. JSON
+/// files may contain comments and other JSON5 extensions.
+fn parse_configuration(contents: &str, path: &Path) -> Result<Value> {
+    match get_extension(path).as_deref() {
+        Some("toml") => toml::from_str(contents).map_err(Error::InvalidToml),
+        Some("yaml" | "yml") => serde_yaml::from_str(contents).map_err(Error::InvalidYaml),
+        Some("rules") => parse_rules(contents, path),
+        _ => json5::from_str(contents).map_err(Error::InvalidJson5),
+    }
+}
+
+/// Write a configuration back out in the format implied by the file extension.
+fn format_configuration(configuration: &Value, path: &Path) -> Result<String> {
+    let formatted = match get_extension(path).as_deref() {
+        Some("toml") => toml::to_string_pretty(configuration).map_err(|err| err.into()),
+        Some("yaml" | "yml") => serde_yaml::to_string(configuration).map_err(|err| err.into()),
+        Some("rules") => return Err(Error::CantMigrateRules),
+        _ => serde_json::to_string_pretty(configuration).map_err(|err| err.into()),
+    };
+    formatted.map_err(Error::CantWriteConfiguration)
+}
+
+fn get_extension(path: &Path) -> Option<String> {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+}
+
+/// Parse a rules file, where each line is a selector of the form `pattern => profile`, and `#` starts a comment.
+/// Rules can use the name of a browser like `Firefox` as their profile without declaring it, which opens the browser
+/// without specifying a browser profile.
+fn parse_rules(contents: &str, path: &Path) -> Result<Value> {
+    let mut profiles = Map::new();
+    let mut profile_selection = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = match line.find('#') {
+            // Only treat `#` as a comment when it's not part of the pattern, e.g. in `https://example.com/#/*`
+            Some(comment_start)
+                if comment_start == 0 || line[..comment_start].ends_with(char::is_whitespace) =>
+            {
+                &line[..comment_start]
+            }
+            _ => line,
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (pattern, profile) = line
+            .split_once("=>")
+            .map(|(pattern, profile)| (pattern.trim(), profile.trim()))
+            .filter(|(pattern, profile)| !pattern.is_empty() && !profile.is_empty())
+            .ok_or_else(|| Error::InvalidRuleSyntax(path.to_path_buf(), line_number))?;
+        Pattern::new(pattern, true)
+            .map_err(|err| Error::InvalidRulePattern(path.to_path_buf(), line_number, err))?;
+
+        if IMPLICIT_BROWSER_PROFILES.contains(&profile) {
+            let mut browser = Map::new();
+            browser.insert("browser".to_string(), Value::String(profile.to_string()));
+            profiles.insert(profile.to_string(), Value::Object(browser));
+        }
+
+        let mut selector = Map::new();
+        selector.insert("profile".to_string(), Value::String(profile.to_string()));
+        selector.insert("pattern".to_string(), Value::String(pattern.to_string()));
+        profile_selection.push(Value::Object(selector));
+    }
+
+    let mut rules = Map::new();
+    rules.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    rules.insert("profiles".to_string(), Value::Object(profiles));
+    rules.insert(
+        "profile_selection".to_string(),
+        Value::Array(profile_selection),
+    );
+    Ok(Value::Object(rules))
+}
+
+/// Migrations from each older version of the configuration format to the next one. The first entry will upgrade
+/// version 1 (which has no `version` field) to version 2, and so on. There are none yet, since the format hasn't
+/// changed.
+const MIGRATIONS: [fn(&mut Map<String, Value>); 0] = [];
+
+/// Upgrade a configuration to the current version of the format. Returns the version it was upgraded from, if it
+/// wasn't already the current version.
+fn migrate(configuration: &mut Value) -> Result<Option<u64>> {
+    let Some(declarations) = configuration.as_object_mut() else {
+        return Ok(None);
+    };
+
+    let version: u64 = match declarations.get("version") {
+        Some(version) => serde_json::from_value(version.clone()).map_err(Error::InvalidJson)?,
+        None => 1,
+    };
+    if version > CONFIG_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    } else if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    for migration in MIGRATIONS.iter().skip(version.saturating_sub(1) as usize) {
+        migration(declarations);
+    }
+
+    // Put the version first, so that it's easy to spot
+    let mut migrated = Map::new();
+    migrated.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    migrated.extend(
+        std::mem::take(declarations)
+            .into_iter()
+            .filter(|(key, _)| key != "version"),
+    );
+    *declarations = migrated;
+
+    Ok(Some(version))
+}
+
+/// A configuration file rewritten to the current version of the format
+pub struct Migration {
+    pub from_version: u64,
+    pub original: String,
+    pub migrated: String,
+}
+
+/// Rewrite the configuration file at `path` to the current version of the format, keeping the order of profiles and
+/// selectors. Returns `None` if it's already the current version. Like `ConfigurationEditor::open`, this refuses to
+/// rewrite a file with comments unless `force` is set.
+pub fn migrate_file(path: &Path, force: bool) -> Result<Option<Migration>> {
+    let original = std::fs::read_to_string(path).map_err(Error::InvalidFile)?;
+    let mut configuration = parse_configuration(&original, path)?;
+    let Some(from_version) = migrate(&mut configuration)? else {
+        return Ok(None);
+    };
+    if !force && has_comments(&original, path) {
+        return Err(Error::WouldRemoveComments(path.to_path_buf()));
+    }
+
+    let mut migrated = format_configuration(&configuration, path)?;
+    if !migrated.ends_with('\n') {
+        migrated.push('\n');
+    }
+    Ok(Some(Migration {
+        from_version,
+        original,
+        migrated,
+    }))
+}
+
+/// A configuration file being edited by the `rules` and `profiles` commands. Edits keep the order of everything else
+/// in the file, but not its comments.
+pub struct ConfigurationEditor {
+    path: PathBuf,
+    original: String,
+    configuration: Map<String, Value>,
+}
+
+/// The contents of a configuration file before and after editing it
+pub struct EditedConfiguration {
+    pub original: String,
+    pub edited: String,
+}
+
+impl ConfigurationEditor {
+    /// Start editing the configuration file at `path`, or a new, empty one if it doesn't exist yet. Refuses to edit a
+    /// file with comments unless `force` is set, since they'd be lost.
+    pub fn open(path: &Path, force: bool) -> Result<ConfigurationEditor> {
+        if get_extension(path).as_deref() == Some("rules") {
+            return Err(Error::CantEditRules);
+        }
+
+        let (original, configuration) = if path.is_file() {
+            let original = std::fs::read_to_string(path).map_err(Error::InvalidFile)?;
+            let configuration = parse_configuration(&original, path)?;
+            if !force && has_comments(&original, path) {
+                return Err(Error::WouldRemoveComments(path.to_path_buf()));
+            }
+            (original, configuration)
+        } else {
+            let configuration = serde_json::json!({
+                "version": CONFIG_VERSION,
+                "profiles": {},
+                "profile_selection": [],
+            });
+            (String::new(), configuration)
+        };
+        let Value::Object(configuration) = configuration else {
+            return Err(Error::InvalidJson(serde::de::Error::custom(
+                "configuration is not an object",
+            )));
+        };
+
+        Ok(ConfigurationEditor {
+            path: path.to_path_buf(),
+            original,
+            configuration,
+        })
+    }
+
+    fn selectors(&mut self) -> &mut Vec<Value> {
+        let selectors = self
+            .configuration
+            .entry("profile_selection")
+            .or_insert_with(|| Value::Array(Vec::new()));
+        if !selectors.is_array() {
+            *selectors = Value::Array(Vec::new());
+        }
+        selectors.as_array_mut().unwrap()
+    }
+
+    fn profiles(&mut self) -> &mut Map<String, Value> {
+        let profiles = self
+            .configuration
+            .entry("profiles")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !profiles.is_object() {
+            *profiles = Value::Object(Map::new());
+        }
+        profiles.as_object_mut().unwrap()
+    }
+
+    /// Find the position of the only selector with `pattern`.
+    fn find_rule(&self, pattern: &str) -> Result<usize> {
+        let positions: Vec<usize> = self
+            .configuration
+            .get("profile_selection")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .enumerate()
+            .filter(|(_, selector)| {
+                selector.get("pattern").and_then(Value::as_str) == Some(pattern)
+            })
+            .map(|(position, _)| position)
+            .collect();
+        match positions.as_slice() {
+            [] => Err(Error::MissingRule(pattern.to_string())),
+            [position] => Ok(*position),
+            _ => Err(Error::AmbiguousRule(pattern.to_string(), positions.len())),
+        }
+    }
+
+    /// Add a selector that opens URLs matching `pattern` with `profile`, before the selector at position `before`
+    /// (starting at 0), or after all of them.
+    pub fn add_rule(&mut self, pattern: &str, profile: &str, before: Option<usize>) -> Result<()> {
+        let selectors = self.selectors();
+        let position = before.unwrap_or(selectors.len());
+        if position > selectors.len() {
+            return Err(Error::InvalidRulePosition(position, selectors.len()));
+        }
+
+        let selector = serde_json::json!({ "profile": profile, "pattern": pattern });
+        selectors.insert(position, selector);
+        Ok(())
+    }
+
+    /// Remove every selector with `pattern`.
+    pub fn remove_rule(&mut self, pattern: &str) -> Result<()> {
+        let selectors = self.selectors();
+        let count = selectors.len();
+        selectors
+            .retain(|selector| selector.get("pattern").and_then(Value::as_str) != Some(pattern));
+        if selectors.len() == count {
+            return Err(Error::MissingRule(pattern.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Move the selector with `pattern` to position `to`, starting at 0.
+    pub fn move_rule(&mut self, pattern: &str, to: usize) -> Result<()> {
+        let from = self.find_rule(pattern)?;
+        let selectors = self.selectors();
+        if to >= selectors.len() {
+            return Err(Error::InvalidRulePosition(to, selectors.len()));
+        }
+
+        let selector = selectors.remove(from);
+        selectors.insert(to, selector);
+        Ok(())
+    }
+
+    /// Declare a new profile called `name`.
+    pub fn add_profile(&mut self, name: &str, profile: Map<String, Value>) -> Result<()> {
+        let profiles = self.profiles();
+        if profiles.contains_key(name) {
+            return Err(Error::DuplicateProfile(name.to_string()));
+        }
+        profiles.insert(name.to_string(), Value::Object(profile));
+        Ok(())
+    }
+
+    /// Remove the profile called `name`. Anything still using it makes the edited configuration invalid.
+    pub fn remove_profile(&mut self, name: &str) -> Result<()> {
+        self.profiles()
+            .shift_remove(name)
+            .map(|_| ())
+            .ok_or_else(|| Error::MissingProfile(name.to_string()))
+    }
+
+    /// Make sure the edited configuration loads the same way `Configuration::read_from_file` would load it, and that
+    /// every selector and the `default_profile` refer to declared profiles. Returns the edited file contents.
+    pub fn finish(self) -> Result<EditedConfiguration> {
+        let configuration = Value::Object(self.configuration);
+        let resolved =
+            resolve_configuration_value(&self.path, configuration.clone(), &mut Vec::new())
+                .and_then(|resolved| merge_rules_file(&self.path, Some(resolved)))
+                .and_then(Configuration::load)
+                .and_then(|loaded| {
+                    let profile_names = loaded
+                        .profile_selection
+                        .iter()
+                        .map(|selector| &selector.profile)
+                        .chain(&loaded.default_profile);
+                    for profile_name in profile_names {
+                        loaded.get_profile(profile_name)?;
+                    }
+                    Ok(())
+                });
+        resolved.map_err(|err| Error::InvalidEdit(Box::new(err)))?;
+
+        let mut edited = match get_extension(&self.path).as_deref() {
+            Some("toml" | "yaml" | "yml") => format_configuration(&configuration, &self.path)?,
+            _ => {
+                let indentation = detect_indentation(&self.original);
+                format_json(&configuration, indentation.as_bytes())?
+            }
+        };
+        if !edited.ends_with('\n') {
+            edited.push('\n');
+        }
+        Ok(EditedConfiguration {
+            original: self.original,
+            edited,
+        })
+    }
+}
+
+/// Check whether `contents` has any comments: `//` and `/* */` in JSON files, and `#` in TOML and YAML files.
+fn has_comments(contents: &str, path: &Path) -> bool {
+    let extension = get_extension(path);
+    let toml = extension.as_deref() == Some("toml");
+    let hash_comments = toml || matches!(extension.as_deref(), Some("yaml" | "yml"));
+    let mut quote = None;
+    let mut previous = '\n';
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match quote {
+            // JSON5 strings and TOML basic strings escape with backslashes, YAML single quoted strings with `''`
+            Some(q) if c == '\\' && (!hash_comments || q == '"') => {
+                chars.next();
+            }
+            Some('\'') if c == '\'' && hash_comments && !toml && chars.peek() == Some(&'\'') => {
+                chars.next();
+            }
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            // YAML allows quotes inside plain scalars, like `it's`, so only treat them as strings where a value starts
+            None if (c == '"' || c == '\'')
+                && (!hash_comments || previous.is_whitespace() || "[{,:=".contains(previous)) =>
+            {
+                quote = Some(c);
+            }
+            None if hash_comments && c == '#' && (toml || previous.is_whitespace()) => return true,
+            None if !hash_comments && c == '/' && matches!(chars.peek(), Some('/' | '*')) => {
+                return true;
+            }
+            None => {}
+        }
+        previous = c;
+    }
+    false
+}
+
+/// Find the indentation used by a JSON file, so we can keep using it when rewriting the file. Defaults to four spaces.
+fn detect_indentation(contents: &str) -> &str {
+    contents
+        .lines()
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .find(|indentation| !indentation.is_empty())
+        .unwrap_or("    ")
+}
+
+/// Pretty print `value` using `indentation` for each level.
+fn format_json(value: &Value, indentation: &[u8]) -> Result<String> {
+    let mut json = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(indentation);
+    let mut serializer = serde_json::Serializer::with_formatter(&mut json, formatter);
+    value
+        .serialize(&mut serializer)
+        .map_err(|err| Error::CantWriteConfiguration(Box::new(err)))?;
+    Ok(String::from_utf8_lossy(&json).into_owned())
+}
+
+/// Record which file each selector (including the ones in `overrides`) was declared in.
+fn tag_selector_sources(configuration: &mut Value, path: &Path) {
+    fn tag(declarations: &mut Value, source: &Value) {
+        let Some(Value::Array(selectors)) = declarations.get_mut("profile_selection") else {
+            return;
+        };
+        for selector in selectors.iter_mut().filter_map(Value::as_object_mut) {
+            selector.insert("source".to_string(), source.clone());
+        }
+    }
+
+    let source = Value::String(path.to_string_lossy().into_owned());
+    tag(configuration, &source);
+    if let Some(Value::Array(overrides)) = configuration.get_mut("overrides") {
+        for entry in overrides {
+            tag(entry, &source);
+        }
+    }
+}
+
+/// Merge `overlay` into `merged`. Profiles and templates in `overlay` replace the ones with the same name, and
+/// `overrides` and `presets` are concatenated. The selectors and rewrites of `overlay` are put before the existing ones
+/// if `selectors_first` is set, otherwise after them. Any other setting in `overlay`, like `default_profile`, replaces
+/// the existing one.
+fn merge_configuration(merged: &mut Map<String, Value>, overlay: Value, selectors_first: bool) {
+    let Value::Object(overlay) = overlay else {
+        return;
+    };
+
+    for (key, value) in overlay {
+        match (key.as_str(), merged.get_mut(&key), value) {
+            (
+                "profiles" | "templates",
+                Some(Value::Object(existing)),
+                Value::Object(declarations),
+            ) => {
+                existing.extend(declarations);
+            }
+            (
+                "profile_selection" | "rewrites",
+                Some(Value::Array(existing)),
+                Value::Array(mut selectors),
+            ) => {
+                if selectors_first {
+                    selectors.append(existing);
+                    *existing = selectors;
+                } else {
+                    existing.append(&mut selectors);
+                }
+            }
+            (
+                "overrides" | "presets",
+                Some(Value::Array(existing)),
+                Value::Array(mut overrides),
+            ) => {
+                existing.append(&mut overrides);
+            }
+            (_, _, value) => {
+                merged.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Conditions for when an entry in `overrides` applies. Each condition can be a single value or a list of values, and
+/// all the conditions that are specified need to match.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct OverrideConditions {
+    /// Operating system, as named by Rust's `std::env::consts::OS`, e.g. `windows` or `macos`
+    pub os: Option<OneOrMany<String>>,
+    pub hostname: Option<OneOrMany<String>>,
+    pub username: Option<OneOrMany<String>>,
+}
+
+impl OverrideConditions {
+    fn is_empty(&self) -> bool {
+        self.os.is_none() && self.hostname.is_none() && self.username.is_none()
+    }
+
+    fn matches(&self) -> bool {
+        fn any_matches(values: &Option<OneOrMany<String>>, actual: impl Fn(&str) -> bool) -> bool {
+            match values {
+                Some(values) => values.as_slice().iter().any(|value| actual(value)),
+                None => true,
+            }
+        }
+
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+        // Accept both the full hostname and the short one, since e.g. macOS likes to append `.local`
+        let short_hostname = hostname.split('.').next().unwrap_or_default();
+        let username = std::env::var("USERNAME")
+            .or_else(|_| std::env::var("USER"))
+            .unwrap_or_default();
+
+        any_matches(&self.os, |os| os.eq_ignore_ascii_case(std::env::consts::OS))
+            && any_matches(&self.hostname, |name| {
+                name.eq_ignore_ascii_case(&hostname) || name.eq_ignore_ascii_case(short_hostname)
+            })
+            && any_matches(&self.username, |name| name.eq_ignore_ascii_case(&username))
+    }
+}
+
+/// The settings an entry in `overrides` can have. `deny_unknown_fields` doesn't work with the flattened conditions, so
+/// these are checked by hand to catch misspelled conditions, which would otherwise make the override apply everywhere.
+const OVERRIDE_SETTINGS: [&str; 7] = [
+    "os",
+    "hostname",
+    "username",
+    "default_profile",
+    "profiles",
+    "templates",
+    "profile_selection",
+];
+
+/// An entry in `overrides`, which replaces profiles and adds selectors when running on a matching machine.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+struct Override {
+    #[serde(flatten)]
+    conditions: OverrideConditions,
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: Map<String, Value>,
+    #[serde(default)]
+    templates: Map<String, Value>,
+    #[serde(default)]
+    profile_selection: Vec<Value>,
+}
+
+/// Apply all the `overrides` that match this machine. Profiles and templates in an override replace the ones with the
+/// same name, and its selectors are checked before the ones in the rest of the configuration.
+fn apply_overrides(configuration: &mut Value) -> Result<()> {
+    let Some(configuration) = configuration.as_object_mut() else {
+        return Ok(());
+    };
+    let Some(overrides) = configuration.remove("overrides") else {
+        return Ok(());
+    };
+    let overrides: Vec<Map<String, Value>> =
+        serde_json::from_value(overrides).map_err(Error::InvalidJson)?;
+
+    let mut profile_selection = Vec::new();
+    for (index, entry) in overrides.into_iter().enumerate() {
+        if let Some(key) = entry
+            .keys()
+            .find(|key| !OVERRIDE_SETTINGS.contains(&key.as_str()))
+        {
+            return Err(Error::UnknownOverrideSetting(index, key.clone()));
+        }
+        let entry: Override =
+            serde_json::from_value(Value::Object(entry)).map_err(Error::InvalidJson)?;
+        if entry.conditions.is_empty() {
+            return Err(Error::UnconditionalOverride(index));
+        }
+        if !entry.conditions.matches() {
+            continue;
+        }
+
+        trace!("applying override for {:?}", entry.conditions);
+        if let Some(default_profile) = entry.default_profile {
+            configuration.insert(
+                "default_profile".to_string(),
+                Value::String(default_profile),
+            );
+        }
+
+        for (key, declarations) in [("profiles", entry.profiles), ("templates", entry.templates)] {
+            if let Value::Object(existing) = configuration
+                .entry(key)
+                .or_insert_with(|| Value::Object(Map::new()))
+            {
+                existing.extend(declarations);
+            }
+        }
+
+        profile_selection.extend(entry.profile_selection);
+    }
+
+    if let Some(Value::Array(existing)) = configuration.get_mut("profile_selection") {
+        profile_selection.append(existing);
+    }
+    configuration.insert(
+        "profile_selection".to_string(),
+        Value::Array(profile_selection),
+    );
+
+    Ok(())
+}
+
+/// Resolve `extends` for each profile, so that it has all the fields of the profile or template it extends except the
+/// ones it overrides. Overriding a field with `null` removes the inherited value. Templates are declared under
+/// `templates`, and are like profiles except they can't be used directly and don't need to specify every field.
+fn resolve_extends(configuration: &mut Value) -> Result<()> {
+    fn resolve(
+        name: &str,
+        entry: &Map<String, Value>,
+        declarations: &[&Map<String, Value>],
+        path: &mut Vec<String>,
+    ) -> Result<Map<String, Value>> {
+        let mut entry = entry.clone();
+        let parent_name = match entry.remove("extends") {
+            Some(Value::String(parent_name)) => parent_name,
+            Some(parent) => {
+                return Err(Error::UnknownParentProfile(
+                    name.to_string(),
+                    parent.to_string(),
+                ))
+            }
+            None => return Ok(entry),
+        };
+
+        if let Some(index) = path.iter().position(|p| *p == parent_name) {
+            let mut cycle = path[index..].to_vec();
+            cycle.push(parent_name);
+            return Err(Error::ExtendsCycle(path[0].clone(), cycle));
+        }
+
+        let parent = declarations
+            .iter()
+            .find_map(|declaration| declaration.get(&parent_name))
+            .and_then(Value::as_object)
+            .ok_or_else(|| Error::UnknownParentProfile(name.to_string(), parent_name.clone()))?;
+
+        path.push(parent_name.clone());
+        let mut merged = resolve(&parent_name, parent, declarations, path)?;
+        path.pop();
+
+        for (key, value) in entry {
+            if value.is_null() {
+                merged.remove(&key);
+            } else {
+                merged.insert(key, value);
+            }
+        }
+
+        Ok(merged)
+    }
+
+    let templates = configuration
+        .get("templates")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    let Some(profiles) = configuration
+        .get_mut("profiles")
+        .and_then(Value::as_object_mut)
+    else {
+        return Ok(());
+    };
+
+    let declared_profiles = profiles.clone();
+    for (name, profile) in profiles.iter_mut() {
+        if let Some(entry) = profile.as_object() {
+            let declarations = [&declared_profiles, &templates];
+            let resolved = resolve(name, entry, &declarations, &mut vec![name.clone()])?;
+            *profile = Value::Object(resolved);
+        }
+    }
+
+    Ok(())
+}
+
+/// A starter configuration, generated from the browsers and browser profiles found on this machine
+#[derive(Debug, Default)]
+pub struct Template {
+    /// Profiles in the order they were discovered
+    profiles: Vec<(String, Profile)>,
+    default_profile: Option<String>,
+}
+
+impl Template {
+    /// Add a profile for `browser`, returning the name it was added under, which has a number appended if the name
+    /// is already taken. The first profile that is added as a default becomes the `default_profile`.
+    pub fn add_profile(&mut self, name: &str, browser: Browser, is_default: bool) -> String {
+        let mut unique_name = name.to_string();
+        let mut suffix = 2;
+        while self
+            .profiles
+            .iter()
+            .any(|(existing, _)| *existing == unique_name)
+        {
+            unique_name = format!("{name} ({suffix})");
+            suffix += 1;
+        }
+
+        if is_default && self.default_profile.is_none() {
+            self.default_profile = Some(unique_name.clone());
+        }
+
+        let profile = Profile {
+            browser,
+            fallback: Vec::new(),
+            strip_query_params: None,
+        };
+        self.profiles.push((unique_name.clone(), profile));
+        unique_name
+    }
+
+    /// Write the configuration as JSON with comments, including some commented out example selectors for the
+    /// discovered profiles.
+    pub fn to_jsonc(&self) -> Result<String> {
+        let mut profiles = Map::new();
+        for (name, profile) in &self.profiles {
+            let profile = serde_json::to_value(profile).map_err(Error::InvalidJson)?;
+            profiles.insert(name.clone(), profile);
+        }
+        let default_profile = self
+            .default_profile
+            .as_ref()
+            .or_else(|| self.profiles.first().map(|(name, _)| name));
+
+        let mut jsonc = String::new();
+        jsonc.push_str("{\n");
+        jsonc
+            .push_str("    // Generated by `bichrome init`, see the README for all the options.\n");
+        jsonc.push_str(&format!("    \"version\": {CONFIG_VERSION},\n"));
+        if let Some(default_profile) = default_profile {
+            let default_profile = to_json(&Value::String(default_profile.clone()), 1)?;
+            jsonc.push_str("    // Used for URLs that don't match any of the selectors\n");
+            jsonc.push_str(&format!("    \"default_profile\": {default_profile},\n"));
+        }
+        let profiles = to_json(&Value::Object(profiles), 1)?;
+        jsonc.push_str(&format!("    \"profiles\": {profiles},\n"));
+        jsonc.push_str("    \"profile_selection\": [\n");
+        jsonc.push_str(
+            "        // The first selector whose pattern matches the URL picks the profile, e.g.\n",
+        );
+        let examples = ["*.example.com", "https://github.com/MyCorp/*"];
+        for ((name, _), pattern) in self.profiles.iter().zip(examples) {
+            let selector = serde_json::json!({ "profile": name, "pattern": pattern });
+            let selector = to_json(&selector, 0)? + ",";
+            for line in selector.lines() {
+                jsonc.push_str(&format!("        // {line}\n"));
+            }
+        }
+        jsonc.push_str("    ]\n");
+        jsonc.push_str("}\n");
+        Ok(jsonc)
+    }
+}
+
+/// Pretty print `value` with four spaces of indentation, for inserting at the given nesting level.
+fn to_json(value: &Value, level: usize) -> Result<String> {
+    let json = format_json(value, b"    ")?;
+    let indentation = "    ".repeat(level);
+    Ok(json.replace('\n', &format!("\n{indentation}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn load_with_policy(configuration: Value, policy: Value) -> Configuration {
+        let policy: Policy = serde_json::from_value(policy).unwrap();
+        policy.validate().unwrap();
+        Configuration::load_with_policy(configuration, policy).unwrap()
+    }
+
+    fn load(configuration: Value) -> Result<Configuration> {
+        Configuration::load_with_policy(configuration, Policy::default())
+    }
+
+    /// Write `files` to an empty directory for `test_name`, and return its path.
+    fn config_dir(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let config_path = std::env::temp_dir().join(format!(
+            "bichrome-config-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&config_path);
+        std::fs::create_dir_all(&config_path).unwrap();
+        for (filename, contents) in files {
+            std::fs::write(config_path.join(filename), contents).unwrap();
+        }
+        config_path
+    }
+
+    fn selector_patterns(configuration: &Value) -> Vec<&str> {
+        configuration["profile_selection"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|selector| selector["pattern"].as_str().unwrap())
+            .collect()
+    }
+
+    fn profile_names(configuration: &Configuration, url: &str) -> Vec<String> {
+        let url = configuration.prepare_url(url).unwrap();
+        configuration
+            .choose_browser(&url)
+            .unwrap()
+            .into_iter()
+            .map(|candidate| candidate.profile_name)
+            .collect()
+    }
+
+    #[test]
+    fn fallback_order() {
+        let configuration = load(json!({
+            "default_profile": "Work",
+            "profiles": {
+                "Work": { "browser": "Chrome", "fallback": ["Edge", "Personal"] },
+                "Edge": { "browser": "Edge", "fallback": ["Personal"] },
+                "Personal": { "browser": "Firefox" },
+            },
+            "profile_selection": [],
+        }))
+        .unwrap();
+        assert_eq!(
+            profile_names(&configuration, "https://example.com/"),
+            ["Work", "Edge", "Personal"]
+        );
+    }
+
+    #[test]
+    fn fallback_cycle() {
+        let result = load(json!({
+            "profiles": {
+                "Work": { "browser": "Chrome", "fallback": ["Edge"] },
+                "Edge": { "browser": "Edge", "fallback": ["Work"] },
+            },
+            "profile_selection": [],
+        }));
+        match result {
+            Err(Error::FallbackCycle(_, cycle)) => {
+                assert_eq!(cycle.first(), cycle.last());
+                assert_eq!(cycle.len(), 3);
+            }
+            other => panic!("expected a fallback cycle, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_fallback() {
+        let result = load(json!({
+            "profiles": { "Work": { "browser": "Chrome", "fallback": ["Personal"] } },
+            "profile_selection": [],
+        }));
+        assert!(matches!(result, Err(Error::MissingProfile(name)) if name == "Personal"));
+    }
+
+    #[test]
+    fn extends_profiles_and_templates() {
+        let mut configuration = json!({
+            "templates": {
+                "Corp": { "browser": "Chrome", "hosted_domain": "corp.com", "fallback": ["Personal"] },
+            },
+            "profiles": {
+                "Work": { "extends": "Corp", "fallback": null },
+                "Sales": { "extends": "Work", "hosted_domain": "sales.corp.com" },
+                "Personal": { "browser": "Firefox" },
+            },
+        });
+        resolve_extends(&mut configuration).unwrap();
+        assert_eq!(
+            configuration["profiles"],
+            json!({
+                "Work": { "browser": "Chrome", "hosted_domain": "corp.com" },
+                "Sales": { "browser": "Chrome", "hosted_domain": "sales.corp.com" },
+                "Personal": { "browser": "Firefox" },
+            })
+        );
+    }
+
+    #[test]
+    fn extends_cycle() {
+        let mut configuration = json!({
+            "templates": { "Corp": { "extends": "Work" } },
+            "profiles": { "Work": { "extends": "Corp" } },
+        });
+        match resolve_extends(&mut configuration) {
+            Err(Error::ExtendsCycle(name, cycle)) => {
+                assert_eq!(name, "Work");
+                assert_eq!(cycle, ["Work", "Corp", "Work"]);
+            }
+            other => panic!("expected an extends cycle, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn extends_unknown_profile() {
+        let mut configuration = json!({
+            "profiles": { "Work": { "extends": "Corp" } },
+        });
+        assert!(matches!(
+            resolve_extends(&mut configuration),
+            Err(Error::UnknownParentProfile(name, parent)) if name == "Work" && parent == "Corp"
+        ));
+    }
+
+    #[test]
+    fn includes() {
+        let config_path = config_dir(
+            "includes",
+            &[
+                (
+                    "main.json",
+                    r#"{ "include": ["team.json", "shared/before.json"], "profile_selection": [{ "pattern": "*://main/*", "profile": "Work" }] }"#,
+                ),
+                (
+                    "team.json",
+                    r#"{ "default_profile": "Team", "profile_selection": [{ "pattern": "*://team/*", "profile": "Work" }] }"#,
+                ),
+                (
+                    "leaf.json",
+                    r#"{ "profile_selection": [{ "pattern": "*://leaf/*", "profile": "Work" }] }"#,
+                ),
+            ],
+        );
+        std::fs::create_dir_all(config_path.join("shared")).unwrap();
+        std::fs::write(
+            config_path.join("shared/before.json"),
+            r#"{ "include": "../leaf.json", "included_rules": "Before", "profile_selection": [{ "pattern": "*://before/*", "profile": "Work" }] }"#,
+        )
+        .unwrap();
+
+        let configuration =
+            read_configuration_value(&config_path.join("main.json"), &mut Vec::new()).unwrap();
+        assert_eq!(configuration["default_profile"], "Team");
+        // Included selectors go after the ones of the including file, unless it asks for them to go before
+        assert_eq!(
+            selector_patterns(&configuration),
+            ["*://main/*", "*://team/*", "*://leaf/*", "*://before/*"]
+        );
+    }
+
+    #[test]
+    fn include_cycle() {
+        let config_path = config_dir(
+            "include_cycle",
+            &[
+                ("a.json", r#"{ "include": "b.json" }"#),
+                ("b.json", r#"{ "include": "a.json" }"#),
+            ],
+        );
+        let mut error =
+            read_configuration_value(&config_path.join("a.json"), &mut Vec::new()).unwrap_err();
+        while let Error::InvalidInclude(_, source) = error {
+            error = *source;
+        }
+        match error {
+            Error::IncludeCycle(_, cycle) => {
+                let names: Vec<_> = cycle
+                    .iter()
+                    .map(|path| path.file_name().unwrap().to_str().unwrap())
+                    .collect();
+                assert_eq!(names, ["a.json", "b.json", "a.json"]);
+            }
+            other => panic!("expected an include cycle, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn missing_include() {
+        let config_path = config_dir(
+            "missing_include",
+            &[("main.json", r#"{ "include": "missing.json" }"#)],
+        );
+        let error =
+            read_configuration_value(&config_path.join("main.json"), &mut Vec::new()).unwrap_err();
+        assert!(matches!(
+            error,
+            Error::InvalidInclude(path, source)
+                if path.ends_with("missing.json") && matches!(*source, Error::InvalidFile(_))
+        ));
+    }
+
+    #[test]
+    fn policy_selectors_use_policy_profiles() {
+        let policy: Policy = serde_json::from_value(json!({
+            "profile_selection": [{ "pattern": "*://*.mycorp.com/*", "profile": "Work" }],
+        }))
+        .unwrap();
+        assert!(matches!(
+            policy.validate(),
+            Err(Error::UndeclaredPolicyProfile(0, name)) if name == "Work"
+        ));
+    }
+
+    #[test]
+    fn policy_fallbacks_use_policy_profiles() {
+        let policy: Policy = serde_json::from_value(json!({
+            "profiles": { "Work": { "browser": "Chrome", "fallback": ["Personal"] } },
+            "profile_selection": [{ "pattern": "*://*.mycorp.com/*", "profile": "Work" }],
+        }))
+        .unwrap();
+        assert!(matches!(
+            policy.validate(),
+            Err(Error::UndeclaredPolicyFallback(name, fallback)) if name == "Work" && fallback == "Personal"
+        ));
+    }
+
+    #[test]
+    fn rules_file() {
+        let path = Path::new("bichrome.rules");
+        let rules = parse_rules(
+            "# Work\n\
+             *://*.mycorp.com/* => Work  # the intranet\n\
+             \n\
+             https://app.example.com/#/* => Chrome\n\
+             *://*.example.org/*=>Firefox#not a comment\n",
+            path,
+        )
+        .unwrap();
+        assert_eq!(
+            rules["profile_selection"],
+            json!([
+                { "profile": "Work", "pattern": "*://*.mycorp.com/*" },
+                { "profile": "Chrome", "pattern": "https://app.example.com/#/*" },
+                { "profile": "Firefox#not a comment", "pattern": "*://*.example.org/*" },
+            ])
+        );
+        // Only the browsers are declared, `Work` has to be declared by the configuration
+        assert_eq!(
+            rules["profiles"],
+            json!({ "Chrome": { "browser": "Chrome" } })
+        );
+    }
+
+    #[test]
+    fn rules_file_errors() {
+        let path = Path::new("bichrome.rules");
+        assert!(matches!(
+            parse_rules("# Work\n*://*.mycorp.com/* Work\n", path),
+            Err(Error::InvalidRuleSyntax(_, 2))
+        ));
+        assert!(matches!(
+            parse_rules("*://*.mycorp.com/* =>\n", path),
+            Err(Error::InvalidRuleSyntax(_, 1))
+        ));
+        assert!(matches!(
+            parse_rules("\n# Work\n*://*.mycorp.com/* => Work\n=> Work\n", path),
+            Err(Error::InvalidRuleSyntax(_, 4))
+        ));
+    }
+
+    #[test]
+    fn locate_rules_only_config() {
+        let config_path = config_dir("locate_rules_only_config", &[]);
+        let search_path = ["empty", "rules", "config"].map(|name| config_path.join(name));
+        for directory in &search_path {
+            std::fs::create_dir_all(directory).unwrap();
+        }
+        std::fs::write(config_path.join("config/bichrome_config.yaml"), "").unwrap();
+        assert_eq!(
+            locate_config_file(None, &search_path[1..]).map(|(path, _)| path),
+            Some(config_path.join("config/bichrome_config.yaml"))
+        );
+
+        std::fs::write(config_path.join("rules/bichrome.rules"), "").unwrap();
+        assert_eq!(
+            locate_config_file(None, &search_path).map(|(path, _)| path),
+            Some(config_path.join("rules/bichrome_config.json"))
+        );
+        assert_eq!(
+            locate_config_file(None, &search_path[..1]).map(|(path, _)| path),
+            Some(config_path.join("empty/bichrome_config.json"))
+        );
+    }
+
+    /// Read a working configuration with `on_config_error`, then break it and read it again.
+    fn read_broken_configuration(test_name: &str, on_config_error: &str) -> Result<Configuration> {
+        let config_path = config_dir(test_name, &[]);
+        let path = config_path.join("bichrome_config.json");
+        let cache_path = config_path.join("last_good.json");
+        let configuration = json!({
+            "default_profile": "Personal",
+            "profiles": { "Personal": { "browser": "Chrome" } },
+            "profile_selection": [],
+            "on_config_error": on_config_error,
+        });
+        std::fs::write(&path, configuration.to_string()).unwrap();
+        let configuration = Configuration::read_with_fallback(&path, Some(&cache_path)).unwrap();
+        assert_eq!(configuration.default_profile.as_deref(), Some("Personal"));
+
+        std::fs::write(&path, "{ \"profiles\": ").unwrap();
+        Configuration::read_with_fallback(&path, Some(&cache_path))
+    }
+
+    #[test]
+    fn broken_configuration_uses_last_good() {
+        let configuration = read_broken_configuration("last_good", "LastGood").unwrap();
+        assert_eq!(configuration.default_profile.as_deref(), Some("Personal"));
+    }
+
+    #[test]
+    fn broken_configuration_uses_default_only() {
+        let configuration = read_broken_configuration("default_only", "DefaultOnly").unwrap();
+        assert_eq!(configuration.default_profile, None);
+        assert!(configuration.profiles.is_empty());
+    }
+
+    #[test]
+    fn broken_configuration_is_refused() {
+        assert!(matches!(
+            read_broken_configuration("refuse", "Refuse"),
+            Err(Error::RefusedBrokenConfiguration(_))
+        ));
+    }
+
+    #[test]
+    fn broken_configuration_without_last_good() {
+        let config_path = config_dir("without_last_good", &[("bichrome_config.json", "{")]);
+        let configuration = Configuration::read_with_fallback(
+            &config_path.join("bichrome_config.json"),
+            Some(&config_path.join("last_good.json")),
+        )
+        .unwrap();
+        assert_eq!(configuration.default_profile, None);
+    }
+
+    #[test]
+    fn forbidden_default_profile() {
+        let configuration = load_with_policy(
+            json!({
+                "default_profile": "Personal",
+                "profiles": { "Personal": { "browser": "Chrome" } },
+                "profile_selection": [],
+            }),
+            json!({
+                "forbid": [{ "pattern": "*://*.mycorp.com/*", "profiles": ["Personal"] }],
+            }),
+        );
+        let url = configuration
+            .prepare_url("https://intranet.mycorp.com/")
+            .unwrap();
+        assert!(matches!(
+            configuration.select_profile(&url).unwrap(),
+            Selection::Forbidden
+        ));
+        assert!(profile_names(&configuration, "https://intranet.mycorp.com/").is_empty());
+        assert_eq!(
+            profile_names(&configuration, "https://example.com/"),
+            ["Personal"]
+        );
+    }
+
+    #[test]
+    fn forbidden_implicit_default() {
+        let configuration = load_with_policy(
+            json!({ "profiles": {}, "profile_selection": [] }),
+            json!({
+                "forbid": [{ "pattern": "*://*.mycorp.com/*", "profiles": ["Personal"] }],
+            }),
+        );
+        assert!(profile_names(&configuration, "https://intranet.mycorp.com/").is_empty());
+        assert_eq!(
+            profile_names(&configuration, "https://example.com/"),
+            [IMPLICIT_DEFAULT_PROFILE]
+        );
+    }
+
+    #[test]
+    fn builtin_redirectors() {
+        let configuration = load(json!({
+            "profiles": { "Personal": { "browser": "Chrome" } },
+            "profile_selection": [],
+            "unwrap_redirects": {},
+        }))
+        .unwrap();
+        let unwrapped = |url| configuration.prepare_url(url).unwrap().unwrapped_url;
+        assert_eq!(
+            unwrapped("https://eur01.safelinks.protection.outlook.com/?url=https%3A%2F%2Fexample.com%2Fa%3Fb%3Dc&data=x"),
+            "https://example.com/a?b=c"
+        );
+        assert_eq!(
+            unwrapped("https://www.google.com/url?q=https://example.com/&sa=D"),
+            "https://example.com/"
+        );
+        assert_eq!(
+            unwrapped("https://www.youtube.com/redirect?q=https://example.com/"),
+            "https://example.com/"
+        );
+        // Not a redirector, or no URL in its parameter
+        assert_eq!(
+            unwrapped("https://www.google.com/search?q=https://example.com/"),
+            "https://www.google.com/search?q=https://example.com/"
+        );
+        assert_eq!(
+            unwrapped("https://www.google.com/url?q=example"),
+            "https://www.google.com/url?q=example"
+        );
+    }
+
+    #[test]
+    fn redirectors_are_unwrapped_recursively() {
+        let configuration = load(json!({
+            "profiles": { "Personal": { "browser": "Chrome" } },
+            "profile_selection": [],
+            "unwrap_redirects": { "launch": "Original" },
+        }))
+        .unwrap();
+        let received = "https://www.google.com/url?q=https%3A%2F%2Fslack-redir.net%2Flink%3Furl%3Dhttps%253A%252F%252Fexample.com%252F";
+        let url = configuration.prepare_url(received).unwrap();
+        let steps: Vec<&str> = url.steps.iter().map(RewriteStep::url).collect();
+        assert_eq!(
+            steps,
+            [
+                "https://slack-redir.net/link?url=https%3A%2F%2Fexample.com%2F",
+                "https://example.com/"
+            ]
+        );
+        assert_eq!(url.url, "https://example.com/");
+        assert_eq!(url.launch_url, received);
+    }
+
+    #[test]
+    fn custom_redirectors_come_first() {
+        let url = "https://www.google.com/url?q=https://builtin.com/&target=https://custom.com/";
+        let unwrapped = |unwrap_redirects| {
+            load(json!({
+                "profiles": { "Personal": { "browser": "Chrome" } },
+                "profile_selection": [],
+                "unwrap_redirects": unwrap_redirects,
+            }))
+            .unwrap()
+            .prepare_url(url)
+            .unwrap()
+            .unwrapped_url
+        };
+        assert_eq!(unwrapped(json!({})), "https://builtin.com/");
+        assert_eq!(
+            unwrapped(json!({
+                "redirectors": [{ "pattern": "*://www.google.com/url?*", "parameter": "target" }],
+            })),
+            "https://custom.com/"
+        );
+        // A custom redirector without its parameter leaves the URL to the built-in ones.
+        assert_eq!(
+            unwrapped(json!({
+                "redirectors": [{ "pattern": "*://www.google.com/url?*", "parameter": "missing" }],
+            })),
+            "https://builtin.com/"
+        );
+    }
+
+    #[test]
+    fn rewrites_are_normalized() {
+        let configuration = load(json!({
+            "profiles": {},
+            "profile_selection": [],
+            "rewrites": [
+                { "regex": "^https://(www\\.)?example\\.com/?$", "replacement": "https://example.com" },
+                { "regex": "^https://old\\.example\\.com/(.*)$", "replacement": "https://new.example.com/$1 x" },
+            ],
+        }))
+        .unwrap();
+        let url = configuration
+            .prepare_url("https://www.example.com/")
+            .unwrap();
+        assert_eq!(url.url, "https://example.com/");
+        assert_eq!(url.launch_url, "https://example.com/");
+
+        let url = configuration
+            .prepare_url("https://old.example.com/a")
+            .unwrap();
+        assert_eq!(url.launch_url, "https://new.example.com/a%20x");
+    }
+
+    #[test]
+    fn rewrite_loop() {
+        let configuration = load(json!({
+            "profiles": {},
+            "profile_selection": [],
+            "rewrites": [
+                { "pattern": "*://a.example.com/*", "replacement": "https://b.example.com{path}" },
+                { "pattern": "*://b.example.com/*", "replacement": "https://a.example.com{path}" },
+            ],
+        }))
+        .unwrap();
+        match configuration.prepare_url("https://a.example.com/page") {
+            Err(Error::RewriteLoop(urls)) => assert_eq!(
+                urls,
+                [
+                    "https://a.example.com/page",
+                    "https://b.example.com/page",
+                    "https://a.example.com/page",
+                ]
+            ),
+            other => panic!("expected a rewrite loop, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn rewrites_cant_move_pinned_urls() {
+        let configuration = load_with_policy(
+            json!({
+                "profiles": { "Personal": { "browser": "Chrome" } },
+                "profile_selection": [{ "pattern": "*://*.personal.com/*", "profile": "Personal" }],
+                "rewrites": [{ "pattern": "*://*.mycorp.com/*", "replacement": "https://www.personal.com/" }],
+            }),
+            json!({
+                "profiles": { "Work": { "browser": "Edge" } },
+                "profile_selection": [{ "pattern": "*://*.mycorp.com/*", "profile": "Work" }],
+            }),
+        );
+        let url = configuration
+            .prepare_url("https://intranet.mycorp.com/")
+            .unwrap();
+        assert!(url.steps.is_empty());
+        assert_eq!(url.launch_url, "https://intranet.mycorp.com/");
+        assert_eq!(
+            profile_names(&configuration, "https://intranet.mycorp.com/"),
+            ["Work"]
+        );
+    }
+
+    #[test]
+    fn rewrites_cant_bypass_forbidden_profiles() {
+        let configuration = load_with_policy(
+            json!({
+                "profiles": { "Personal": { "browser": "Chrome" } },
+                "profile_selection": [{ "pattern": "*://*.personal.com/*", "profile": "Personal" }],
+                "rewrites": [{ "pattern": "*://*.mycorp.com/*", "replacement": "https://www.personal.com/" }],
+            }),
+            json!({
+                "forbid": [{ "pattern": "*://*.mycorp.com/*", "profiles": ["Personal"] }],
+            }),
+        );
+        assert!(profile_names(&configuration, "https://intranet.mycorp.com/").is_empty());
+    }
+
+    #[test]
+    fn launching_the_original_url_follows_the_policy() {
+        let configuration = load_with_policy(
+            json!({
+                "default_profile": "Personal",
+                "profiles": { "Personal": { "browser": "Chrome" } },
+                "profile_selection": [],
+                "unwrap_redirects": {
+                    "launch": "Original",
+                    "redirectors": [
+                        { "pattern": "*://sso.mycorp.com/*", "parameter": "return_to" },
+                        { "pattern": "*://*.workday.com/*", "parameter": "x" },
+                    ],
+                },
+            }),
+            json!({
+                "profiles": { "Work": { "browser": "Edge" } },
+                "profile_selection": [{ "pattern": "*://sso.mycorp.com/*", "profile": "Work" }],
+                "forbid": [{ "pattern": "*://*.workday.com/*", "profiles": ["Personal"] }],
+            }),
+        );
+        let url = configuration
+            .prepare_url("https://sso.mycorp.com/login?return_to=https://example.com/")
+            .unwrap();
+        assert!(url.steps.is_empty());
+        assert_eq!(
+            profile_names(
+                &configuration,
+                "https://sso.mycorp.com/login?return_to=https://example.com/"
+            ),
+            ["Work"]
+        );
+        assert!(profile_names(
+            &configuration,
+            "https://corp.workday.com/?x=https://example.com/"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn presets_follow_the_policy() {
+        let configuration = load_with_policy(
+            json!({
+                "default_profile": "Personal",
+                "profiles": { "Personal": { "browser": "Chrome" } },
+                "profile_selection": [],
+                "presets": ["zoom-desktop"],
+            }),
+            json!({
+                "profiles": { "Work": { "browser": "Edge" } },
+                "profile_selection": [{ "pattern": "*://pinned.zoom.us/*", "profile": "Work" }],
+                "forbid": [{ "pattern": "*://mycorp.zoom.us/*", "profiles": ["zoom-desktop"] }],
+            }),
+        );
+        assert_eq!(
+            profile_names(&configuration, "https://pinned.zoom.us/j/123"),
+            ["Work"]
+        );
+        assert_eq!(
+            profile_names(&configuration, "https://mycorp.zoom.us/j/123"),
+            ["Personal"]
+        );
+        assert_eq!(
+            profile_names(&configuration, "https://us02web.zoom.us/j/123"),
+            ["zoom-desktop"]
+        );
+    }
+
+    fn stripped(strip_query_params: Value, url: &str) -> String {
+        let strip_query_params: StripQueryParams =
+            serde_json::from_value(strip_query_params).unwrap();
+        let mut url = Url::parse(url).unwrap();
+        strip_query_params.apply(&mut url);
+        url.into()
+    }
+
+    #[test]
+    fn strip_query_params() {
+        assert_eq!(
+            stripped(
+                json!(true),
+                "https://example.com/?utm_source=a&id=a%20b&fbclid=c#utm_medium=d"
+            ),
+            "https://example.com/?id=a%20b#utm_medium=d"
+        );
+        assert_eq!(
+            stripped(
+                json!(true),
+                "https://example.com/a?utm_source=a&gclid=b#top"
+            ),
+            "https://example.com/a#top"
+        );
+        assert_eq!(
+            stripped(
+                json!(true),
+                "https://example.com/?utm%5Fsource=a&%66bclid=b&id=c"
+            ),
+            "https://example.com/?id=c"
+        );
+        assert_eq!(
+            stripped(json!(false), "https://example.com/?utm_source=a"),
+            "https://example.com/?utm_source=a"
+        );
+        assert_eq!(
+            stripped(
+                json!({ "remove": ["ref", "trk_*"], "keep": ["utm_campaign", "gclid"] }),
+                "https://example.com/?utm_source=a&utm_campaign=b&gclid=c&ref=d&trk_x=e&id=f"
+            ),
+            "https://example.com/?utm_campaign=b&gclid=c&id=f"
+        );
+    }
+
+    #[test]
+    fn strip_query_params_per_profile() {
+        let configuration = load(json!({
+            "default_profile": "Personal",
+            "strip_query_params": true,
+            "profiles": {
+                "Personal": { "browser": "Chrome", "fallback": ["Work"] },
+                "Work": { "browser": "Edge", "strip_query_params": false },
+            },
+            "profile_selection": [],
+        }))
+        .unwrap();
+        let url = configuration
+            .prepare_url("https://example.com/?utm_source=a&id=b")
+            .unwrap();
+        let urls: Vec<(String, String)> = configuration
+            .choose_browser(&url)
+            .unwrap()
+            .into_iter()
+            .map(|candidate| (candidate.profile_name, candidate.url))
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                (
+                    "Personal".to_string(),
+                    "https://example.com/?id=b".to_string()
+                ),
+                (
+                    "Work".to_string(),
+                    "https://example.com/?utm_source=a&id=b".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn migrate_current_version() {
+        let mut configuration = json!({ "profiles": {} });
+        assert_eq!(migrate(&mut configuration).unwrap(), None);
+        assert_eq!(configuration, json!({ "profiles": {} }));
+
+        let mut configuration = json!({ "version": CONFIG_VERSION + 1, "profiles": {} });
+        assert!(matches!(
+            migrate(&mut configuration),
+            Err(Error::UnsupportedVersion(version)) if version == CONFIG_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn override_for_this_os() {
+        let mut configuration = json!({
+            "default_profile": "Personal",
+            "overrides": [
+                { "os": std::env::consts::OS, "default_profile": "Work" },
+                { "os": "plan9", "default_profile": "Other" },
+            ],
+        });
+        apply_overrides(&mut configuration).unwrap();
+        assert_eq!(configuration["default_profile"], "Work");
+    }
+
+    #[test]
+    fn override_without_condition() {
+        let mut configuration = json!({
+            "overrides": [{ "default_profile": "Work" }],
+        });
+        assert!(matches!(
+            apply_overrides(&mut configuration),
+            Err(Error::UnconditionalOverride(0))
+        ));
+    }
+
+    #[test]
+    fn override_with_misspelled_condition() {
+        let mut configuration = json!({
+            "overrides": [
+                { "os": "windows" },
+                { "hostnme": "work-laptop", "default_profile": "Work" },
+            ],
+        });
+        assert!(matches!(
+            apply_overrides(&mut configuration),
+            Err(Error::UnknownOverrideSetting(1, key)) if key == "hostnme"
+        ));
+    }
 }
@@ -0,0 +1,280 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("could not read Firefox {0}")]
+    InvalidFile(&'static str, #[source] std::io::Error),
+    #[error("could not parse line {1} of Firefox {0}")]
+    InvalidIni(&'static str, usize),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+const PROFILES_INI: &str = "profiles.ini";
+const INSTALLS_INI: &str = "installs.ini";
+
+#[derive(Debug)]
+pub struct FirefoxProfile {
+    pub name: String,
+    pub path: PathBuf,
+    /// Whether this profile is the default profile of any Firefox installation
+    pub is_default: bool,
+}
+
+#[derive(Debug)]
+pub struct ProfilesData {
+    profiles: Vec<FirefoxProfile>,
+}
+
+impl ProfilesData {
+    pub fn profiles(&self) -> &[FirefoxProfile] {
+        &self.profiles
+    }
+
+    pub fn profile_by_name(&self, name: &str) -> Option<&FirefoxProfile> {
+        // Prefer direct profile name matches
+        if let Some(profile) = self.profiles.iter().find(|profile| profile.name == name) {
+            return Some(profile);
+        }
+
+        // Otherwise allow the name of the profile directory, e.g. "abcd1234.default-release"
+        self.profiles
+            .iter()
+            .find(|profile| profile.path.file_name().and_then(|n| n.to_str()) == Some(name))
+    }
+}
+
+/// A minimal representation of an INI file: a list of sections, each with a list of key-value pairs.
+type Ini = Vec<(String, Vec<(String, String)>)>;
+
+fn parse_ini(filename: &'static str, contents: &str) -> Result<Ini> {
+    let mut sections: Ini = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((section.to_owned(), Vec::new()));
+        } else if let (Some((key, value)), Some((_, entries))) =
+            (line.split_once('='), sections.last_mut())
+        {
+            entries.push((key.trim().to_owned(), value.trim().to_owned()));
+        } else {
+            return Err(Error::InvalidIni(filename, index + 1));
+        }
+    }
+
+    Ok(sections)
+}
+
+fn get_value<'a>(entries: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    entries
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+fn read_ini(data_path: &Path, filename: &'static str) -> Result<Option<Ini>> {
+    match fs::read_to_string(data_path.join(filename)) {
+        Ok(contents) => parse_ini(filename, &contents).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::InvalidFile(filename, e)),
+    }
+}
+
+/// Read the profiles from `profiles.ini` (and the per-installation defaults from `installs.ini`)
+/// in the given Firefox data directory.
+pub fn read_profiles_from_dir<P: AsRef<Path>>(data_path: P) -> Result<ProfilesData> {
+    let data_path = data_path.as_ref();
+    let profiles_ini = fs::read_to_string(data_path.join(PROFILES_INI))
+        .map_err(|e| Error::InvalidFile(PROFILES_INI, e))?;
+    let profiles_ini = parse_ini(PROFILES_INI, &profiles_ini)?;
+
+    // Newer versions of Firefox have a dedicated profile per installation, which are listed both in the
+    // `[Install...]` sections of profiles.ini and in installs.ini.
+    let mut install_defaults: Vec<&str> = Vec::new();
+    let installs_ini = read_ini(data_path, INSTALLS_INI)?;
+    let install_sections = profiles_ini
+        .iter()
+        .filter(|(section, _)| section.starts_with("Install"))
+        .chain(installs_ini.iter().flatten());
+    for (_, entries) in install_sections {
+        if let Some(default) = get_value(entries, "Default") {
+            install_defaults.push(default);
+        }
+    }
+
+    let profiles = profiles_ini
+        .iter()
+        .filter(|(section, _)| section.starts_with("Profile"))
+        .filter_map(|(_, entries)| {
+            let name = get_value(entries, "Name")?;
+            let relative_path = get_value(entries, "Path")?;
+            let path = if get_value(entries, "IsRelative") == Some("1") {
+                data_path.join(relative_path)
+            } else {
+                PathBuf::from(relative_path)
+            };
+
+            Some(FirefoxProfile {
+                name: name.to_owned(),
+                path,
+                is_default: install_defaults.contains(&relative_path),
+            })
+        })
+        .collect();
+
+    Ok(ProfilesData { profiles })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `files` to a fresh directory that stands in for Firefox's data directory.
+    fn data_dir(test_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let data_path = std::env::temp_dir().join(format!(
+            "bichrome-firefox-{}-{}",
+            test_name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&data_path);
+        fs::create_dir_all(&data_path).unwrap();
+        for (filename, contents) in files {
+            fs::write(data_path.join(filename), contents).unwrap();
+        }
+        data_path
+    }
+
+    #[test]
+    fn parse_ini_sections() {
+        let ini = parse_ini(
+            PROFILES_INI,
+            "; comment\n[General]\nStartWithLastProfile=1\n\n# comment\n[Profile0]\n Name = default \nPath=a.default\n",
+        )
+        .unwrap();
+        assert_eq!(ini.len(), 2);
+        assert_eq!(ini[0].0, "General");
+        assert_eq!(get_value(&ini[0].1, "StartWithLastProfile"), Some("1"));
+        assert_eq!(ini[1].0, "Profile0");
+        assert_eq!(get_value(&ini[1].1, "Name"), Some("default"));
+        assert_eq!(get_value(&ini[1].1, "Path"), Some("a.default"));
+        assert_eq!(get_value(&ini[1].1, "IsRelative"), None);
+    }
+
+    #[test]
+    fn parse_ini_errors() {
+        assert!(matches!(
+            parse_ini(
+                PROFILES_INI,
+                "[Profile0]\nName=default\nnot a key value pair\n"
+            ),
+            Err(Error::InvalidIni(PROFILES_INI, 3))
+        ));
+        assert!(matches!(
+            parse_ini(PROFILES_INI, "Name=default\n"),
+            Err(Error::InvalidIni(PROFILES_INI, 1))
+        ));
+    }
+
+    #[test]
+    fn relative_and_absolute_paths() {
+        let absolute_path = std::env::temp_dir().join("bichrome-elsewhere.work");
+        let profiles_ini = format!(
+            "[Profile0]\nName=default\nIsRelative=1\nPath=Profiles/a.default\n\n\
+             [Profile1]\nName=work\nIsRelative=0\nPath={}\n",
+            absolute_path.display()
+        );
+        let data_path = data_dir("paths", &[(PROFILES_INI, &profiles_ini)]);
+        let profiles = read_profiles_from_dir(&data_path).unwrap();
+
+        assert_eq!(profiles.profiles().len(), 2);
+        assert_eq!(
+            profiles.profiles()[0].path,
+            data_path.join("Profiles/a.default")
+        );
+        assert_eq!(profiles.profiles()[1].path, absolute_path);
+    }
+
+    #[test]
+    fn install_defaults() {
+        let data_path = data_dir(
+            "installs",
+            &[
+                (
+                    PROFILES_INI,
+                    "[Install4F96D1932A9F858E]\nDefault=Profiles/b.default-release\nLocked=1\n\n\
+                     [Profile0]\nName=default\nIsRelative=1\nPath=Profiles/a.default\nDefault=1\n\n\
+                     [Profile1]\nName=default-release\nIsRelative=1\nPath=Profiles/b.default-release\n\n\
+                     [Profile2]\nName=dev-edition-default\nIsRelative=1\nPath=Profiles/c.dev-edition-default\n",
+                ),
+                (
+                    INSTALLS_INI,
+                    "[46F492E0ACFF84D4]\nDefault=Profiles/c.dev-edition-default\nLocked=1\n",
+                ),
+            ],
+        );
+        let profiles = read_profiles_from_dir(data_path).unwrap();
+        let defaults: Vec<&str> = profiles
+            .profiles()
+            .iter()
+            .filter(|profile| profile.is_default)
+            .map(|profile| profile.name.as_str())
+            .collect();
+        assert_eq!(defaults, ["default-release", "dev-edition-default"]);
+    }
+
+    #[test]
+    fn missing_installs_ini() {
+        let data_path = data_dir(
+            "no-installs",
+            &[(
+                PROFILES_INI,
+                "[Profile0]\nName=default\nIsRelative=1\nPath=Profiles/a.default\n",
+            )],
+        );
+        let profiles = read_profiles_from_dir(data_path).unwrap();
+        assert_eq!(profiles.profiles().len(), 1);
+        assert!(!profiles.profiles()[0].is_default);
+    }
+
+    #[test]
+    fn missing_profiles_ini() {
+        let data_path = data_dir("no-profiles", &[]);
+        assert!(matches!(
+            read_profiles_from_dir(data_path),
+            Err(Error::InvalidFile(PROFILES_INI, _))
+        ));
+    }
+
+    #[test]
+    fn find_profile_by_name() {
+        let data_path = data_dir(
+            "by-name",
+            &[(
+                PROFILES_INI,
+                "[Profile0]\nName=default\nIsRelative=1\nPath=Profiles/a.default\n\n\
+                 [Profile1]\nName=a.default\nIsRelative=1\nPath=Profiles/b.work\n",
+            )],
+        );
+        let profiles = read_profiles_from_dir(data_path).unwrap();
+
+        assert_eq!(profiles.profile_by_name("default").unwrap().name, "default");
+        // Profile names take precedence over directory names.
+        assert_eq!(
+            profiles.profile_by_name("a.default").unwrap().name,
+            "a.default"
+        );
+        assert_eq!(
+            profiles.profile_by_name("b.work").unwrap().name,
+            "a.default"
+        );
+        assert!(profiles.profile_by_name("personal").is_none());
+    }
+}
@@ -1,6 +1,6 @@
-use crate::config::{Browser, Configuration};
-use anyhow::bail;
+use crate::config::{self, Browser, Configuration, FirefoxOptions, GeckoInstallation, GeckoPreset};
 use anyhow::Result;
+use anyhow::{anyhow, bail, Context};
 use fruitbasket::FruitApp;
 use fruitbasket::FruitCallbackKey;
 use fruitbasket::RunPeriod;
@@ -8,7 +8,7 @@ use log::{debug, error, trace, warn};
 use simplelog::*;
 use std::{
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 use url::Url;
@@ -19,6 +19,18 @@ fn get_chrome_binary_path() -> PathBuf {
     PathBuf::from("/Applications/Google Chrome.app/Contents/MacOS/Google Chrome")
 }
 
+/// The name of the application bundle and the name of the binary inside it for each Gecko browser
+fn get_gecko_app(preset: GeckoPreset) -> (&'static str, &'static str) {
+    match preset {
+        GeckoPreset::Firefox => ("Firefox", "firefox"),
+        GeckoPreset::FirefoxDeveloperEdition => ("Firefox Developer Edition", "firefox"),
+        GeckoPreset::FirefoxNightly => ("Firefox Nightly", "firefox"),
+        GeckoPreset::LibreWolf => ("LibreWolf", "librewolf"),
+        GeckoPreset::Floorp => ("Floorp", "floorp"),
+        GeckoPreset::TorBrowser => ("Tor Browser", "firefox"),
+    }
+}
+
 fn get_application_support_path() -> Option<PathBuf> {
     let home_dir = std::env::var_os("HOME")
         .and_then(|h| if h.is_empty() { None } else { Some(h) })
@@ -26,52 +38,98 @@ fn get_application_support_path() -> Option<PathBuf> {
     home_dir.map(|path| path.join("Library/Application Support"))
 }
 
-#[allow(dead_code)]
 pub fn get_chrome_local_state_path() -> Option<PathBuf> {
     get_application_support_path().map(|path| path.join("Google/Chrome/Local State"))
 }
 
+pub fn get_gecko_data_path(preset: GeckoPreset) -> Option<PathBuf> {
+    let app_support_relative = match preset {
+        GeckoPreset::Firefox
+        | GeckoPreset::FirefoxDeveloperEdition
+        | GeckoPreset::FirefoxNightly => "Firefox",
+        GeckoPreset::LibreWolf => "librewolf",
+        GeckoPreset::Floorp => "Floorp",
+        GeckoPreset::TorBrowser => "TorBrowser-Data/Browser",
+    };
+    get_application_support_path().map(|path| path.join(app_support_relative))
+}
+
+pub fn get_policy_path() -> Option<PathBuf> {
+    Some(PathBuf::from("/etc/bichrome/policy.json"))
+}
+
 fn get_log_path() -> Option<PathBuf> {
     get_application_support_path().map(|path| path.join("com.bitspatter.bichrome/bichrome.log"))
 }
 
+/// Find the config from `BICHROME_CONFIG`, or in Application Support, then the XDG config directory.
 fn get_config_path() -> Option<PathBuf> {
+    let search_path: Vec<PathBuf> = get_application_support_path()
+        .map(|path| path.join("com.bitspatter.bichrome"))
+        .into_iter()
+        .chain(config::get_xdg_config_path())
+        .collect();
+    config::locate_config_file(None, &search_path).map(|(path, _)| path)
+}
+
+fn get_config_cache_path() -> Option<PathBuf> {
     get_application_support_path()
-        .map(|path| path.join("com.bitspatter.bichrome/bichrome_config.json"))
+        .map(|path| path.join("com.bitspatter.bichrome/last_good_config.json"))
 }
 
-fn init() -> Configuration {
+fn init() -> Result<Configuration> {
     let config_path = get_config_path();
-    // We try to read the config, and otherwise just use an empty one instead.
+    // If the config is broken, we fall back to the last one that could be read, or to the policy alone.
     match config_path {
         Some(config_path) => {
             debug!("attempting to load config from {}", config_path.display());
-            let config = Configuration::read_from_file(&config_path);
-            match config {
-                Ok(config) => {
-                    trace!("config: {:#?}", config);
-                    config
-                }
-                Err(e) => {
-                    error!("failed to parse config: {:?}", e);
-                    warn!("opening URLs without profile");
-                    Configuration::empty()
-                }
-            }
+            let config = Configuration::read_with_fallback(
+                &config_path,
+                get_config_cache_path().as_deref(),
+            )?;
+            trace!("config: {:#?}", config);
+            Ok(config)
         }
         None => {
             error!("failed to determine config path");
             warn!("opening URLs without profile");
-            Configuration::empty()
+            Ok(Configuration::default_only()?)
         }
     }
 }
 
-fn handle_url(url: &str) -> Result<()> {
-    let config = init();
+fn get_gecko_command(
+    installation: &GeckoInstallation,
+    options: &FirefoxOptions,
+    url: &str,
+) -> Result<(String, Vec<String>)> {
+    let url = options.get_url(url);
+    let mut args = options
+        .profile
+        .get_arguments(installation.get_data_path())?;
+    match installation {
+        GeckoInstallation::Preset { variant } => {
+            let (app_name, binary_name) = get_gecko_app(*variant);
+            if args.is_empty() {
+                // Like for Chrome, `open` is the more natural way to open an URL without a profile.
+                let args = vec!["-a".to_string(), app_name.to_string(), url];
+                Ok(("open".to_string(), args))
+            } else {
+                args.push(url);
+                let exe = format!("/Applications/{app_name}.app/Contents/MacOS/{binary_name}");
+                Ok((exe, args))
+            }
+        }
+        GeckoInstallation::Custom { executable, .. } => {
+            args.push(url);
+            Ok((executable.to_str().unwrap().to_string(), args))
+        }
+    }
+}
 
-    let browser = config.choose_browser(url)?;
-    let (exe, args) = match browser {
+/// Figure out the executable and arguments needed to open `url` in `browser`
+fn get_browser_command(browser: &Browser, url: &str) -> Result<(String, Vec<String>)> {
+    Ok(match browser {
         Browser::Chrome(profile) => {
             if let Some(argument) = profile.get_argument()? {
                 let args = vec![argument, url.to_string()];
@@ -86,14 +144,14 @@ fn handle_url(url: &str) -> Result<()> {
                 ("open".to_string(), args)
             }
         }
-        Browser::Firefox => {
-            // TODO If we support Firefox profiles, use something like the Chrome path with firefox -P <profile>
-            let args = ["-b", "org.mozilla.firefox", url]
-                .iter()
-                .map(|s| s.to_string())
-                .collect();
-            ("open".to_string(), args)
-        }
+        Browser::Firefox(options) => get_gecko_command(
+            &GeckoInstallation::Preset {
+                variant: GeckoPreset::Firefox,
+            },
+            options,
+            url,
+        )?,
+        Browser::Gecko(gecko) => get_gecko_command(&gecko.installation, &gecko.options, url)?,
         Browser::OsDefault | Browser::Safari => {
             let args = ["-b", "com.apple.Safari", url]
                 .iter()
@@ -104,21 +162,138 @@ fn handle_url(url: &str) -> Result<()> {
         Browser::Edge(_) => {
             bail!("Microsoft Edge not supported on macOS")
         }
-        Browser::Executable(location) =>
-            (location.get_path().to_str().unwrap().to_string(), vec![url.to_string()])
-    };
+        Browser::Executable(location) => (
+            location.get_path().to_str().unwrap().to_string(),
+            vec![url.to_string()],
+        ),
+        // `open` without an application opens the URL with the one registered for its scheme.
+        Browser::SystemHandler => ("open".to_string(), vec![url.to_string()]),
+    })
+}
+
+/// Asks Launch Services for the application that opens the URL passed as the first argument, through JavaScript for
+/// Automation since there's no command for it.
+const URL_HANDLER_SCRIPT: &str = r#"ObjC.import("AppKit");
+function run(argv) {
+    const url = $.NSURL.URLWithString(argv[0]);
+    const application = $.NSWorkspace.sharedWorkspace.URLForApplicationToOpenURL(url);
+    return application.isNil() ? "" : application.path.js;
+}"#;
+
+/// Find the application bundle registered to open `url`.
+fn get_url_handler(url: &str) -> Result<PathBuf> {
+    let output = Command::new("osascript")
+        .args(["-l", "JavaScript", "-e", URL_HANDLER_SCRIPT, url])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .context("could not run osascript")?;
+    let handler = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || handler.is_empty() {
+        bail!("no application is registered for the URL");
+    }
+    Ok(PathBuf::from(handler))
+}
+
+/// The URL schemes bichrome registers for in its `Info.plist`, so it can't be the handler for any other scheme
+const REGISTERED_SCHEMES: [&str; 2] = ["http", "https"];
+
+/// Whether the application bundle at `handler` contains the bichrome at `exe_path`. Paths that can't be
+/// canonicalized are compared as they are, so that a handler registered with the same path still counts as bichrome.
+fn is_bichrome(handler: &Path, exe_path: &Path) -> bool {
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    exe_path.starts_with(handler) || canonical(exe_path).starts_with(canonical(handler))
+}
+
+/// Make sure that the application `open` would hand `url` to isn't bichrome itself, which would just hand it back to
+/// us. `get_handler` finds the application registered to open a URL, like `get_url_handler`.
+fn check_system_handler(
+    url: &str,
+    get_handler: impl FnOnce(&str) -> Result<PathBuf>,
+) -> Result<()> {
+    let scheme = url.split_once(':').map_or(url, |(scheme, _)| scheme);
+    // bichrome also registers for document types, and which application opens a file: URL depends on its extension
+    // rather than its scheme.
+    if scheme.eq_ignore_ascii_case("file") {
+        bail!("bichrome is registered for files too, so it can't hand off file: URLs");
+    }
+    // Finding the handler runs osascript, which isn't worth it when bichrome can't be the handler anyway.
+    if !REGISTERED_SCHEMES
+        .iter()
+        .any(|registered| registered.eq_ignore_ascii_case(scheme))
+    {
+        return Ok(());
+    }
+
+    let handler = get_handler(url)?;
+    debug!("URL is handled by {}", handler.display());
+    if is_bichrome(&handler, &std::env::current_exe()?) {
+        bail!("bichrome is the registered handler for the URL, so it can't hand it off");
+    }
+    Ok(())
+}
+
+/// Launch `browser` to open `url`, returning an error describing why if we can't.
+fn open_url(browser: &Browser, url: &str) -> Result<()> {
+    if let Browser::SystemHandler = browser {
+        check_system_handler(url, get_url_handler)?;
+    }
+
+    let (exe, args) = get_browser_command(browser, url).map_err(|error| {
+        // Errors from the configuration are from resolving browser profiles, anything else means we
+        // couldn't find the browser.
+        if error.is::<config::Error>() {
+            error.context("profile not found")
+        } else {
+            error.context("browser not installed")
+        }
+    })?;
 
     debug!("launching \"{}\" \"{}\"", exe, args.join("\" \""));
-    Command::new(&exe)
+    let mut command = Command::new(&exe);
+    command
         .stdout(Stdio::null())
         .stdin(Stdio::null())
         .stderr(Stdio::null())
-        .args(args)
-        .spawn()?;
+        .args(args);
+
+    if exe == "open" {
+        // `open` exits quickly, and fails if it can't find the application, so wait for it to find out whether we
+        // need to fall back to another browser.
+        let status = command.status().context("launch failed")?;
+        if !status.success() {
+            return Err(anyhow!("`open` exited with {}", status).context("browser not installed"));
+        }
+    } else if !Path::new(&exe).is_file() {
+        return Err(anyhow!("could not find {}", exe).context("browser not installed"));
+    } else {
+        command.spawn().context("launch failed")?;
+    }
 
     Ok(())
 }
 
+fn handle_url(url: &str) -> Result<()> {
+    let config = init()?;
+
+    let prepared_url = config.prepare_url(url)?;
+    let candidates = config.choose_browser(&prepared_url)?;
+    for candidate in &candidates {
+        match open_url(&candidate.browser, &candidate.url) {
+            Ok(()) => return Ok(()),
+            Err(error) => warn!("skipping profile '{}': {:#}", candidate.profile_name, error),
+        }
+    }
+
+    bail!(
+        "Could not open URL with any of the profiles {:?}",
+        candidates
+            .iter()
+            .map(|candidate| &candidate.profile_name)
+            .collect::<Vec<_>>()
+    );
+}
+
 pub fn main() -> Result<()> {
     let log_level = LevelFilter::Debug;
     let log_path = get_log_path().unwrap();
@@ -180,3 +355,50 @@ pub fn main() -> Result<()> {
     // This will never execute.
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_handler_is_bichrome() {
+        // The test binary stands in for bichrome, so its directory stands in for the application bundle.
+        let bundle = || Ok(std::env::current_exe()?.parent().unwrap().to_path_buf());
+        assert!(check_system_handler("https://example.com/", |_| bundle()).is_err());
+        assert!(check_system_handler("HTTP://example.com/", |_| bundle()).is_err());
+    }
+
+    #[test]
+    fn system_handler_is_another_application() {
+        let other = std::env::temp_dir().join("bichrome-test-handler.app");
+        std::fs::create_dir_all(&other).unwrap();
+        let handler = |_: &str| Ok(other.clone());
+        assert!(check_system_handler("https://example.com/", handler).is_ok());
+    }
+
+    #[test]
+    fn system_handler_is_bichrome_at_a_missing_path() {
+        let bundle = std::env::temp_dir().join("bichrome-missing.app");
+        assert!(is_bichrome(
+            &bundle,
+            &bundle.join("Contents/MacOS/bichrome")
+        ));
+        assert!(!is_bichrome(
+            &bundle,
+            Path::new("/Applications/bichrome.app/Contents/MacOS/bichrome")
+        ));
+    }
+
+    #[test]
+    fn system_handler_refuses_files() {
+        let handler = |_: &str| -> Result<PathBuf> { panic!("looked up the handler") };
+        assert!(check_system_handler("file:///Users/me/report.pdf", handler).is_err());
+        assert!(check_system_handler("FILE:///Users/me/report.pdf", handler).is_err());
+    }
+
+    #[test]
+    fn system_handler_for_unregistered_scheme() {
+        let handler = |_: &str| -> Result<PathBuf> { panic!("looked up the handler") };
+        assert!(check_system_handler("zoommtg://zoom.us/join?confno=123", handler).is_ok());
+    }
+}
@@ -9,6 +9,8 @@
 
 mod chrome_local_state;
 mod config;
+mod firefox_profiles;
+mod presets;
 
 #[cfg(target_os = "macos")]
 mod macos;
@@ -0,0 +1,321 @@
+use regex::Regex;
+use std::sync::OnceLock;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("unknown preset '{0}'")]
+    UnknownPreset(String),
+    #[error("preset '{0}' has no version {1}")]
+    UnknownVersion(String, u32),
+    #[error("invalid preset '{0}', expected `name` or `name@version`")]
+    InvalidName(String),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A built-in bundle of conversions from web links to links that open a desktop app, which are handed to the OS to
+/// open with the app registered for the preset's scheme
+#[derive(Debug)]
+pub struct Preset {
+    pub name: &'static str,
+    /// A preset's conversions never change within a version, so a configuration can pin one with `name@version`
+    pub version: u32,
+    /// The URL scheme the app is registered for
+    pub scheme: &'static str,
+    /// Regular expressions matching the app's web links, and what to replace them with. The first one that matches is
+    /// used.
+    conversions: &'static [(&'static str, &'static str)],
+    /// `conversions` with their regexes compiled, the first time the preset is used
+    compiled: OnceLock<Vec<(Regex, &'static str)>>,
+}
+
+pub static PRESETS: [Preset; 5] = [
+    Preset {
+        name: "zoom-desktop",
+        version: 1,
+        scheme: "zoommtg",
+        conversions: &[
+            (
+                r"^https://(?:[\w-]+\.)?zoom\.us/j/(\d+)\?(?:.*&)?pwd=([\w.-]+).*$",
+                "zoommtg://zoom.us/join?action=join&confno=$1&pwd=$2",
+            ),
+            (
+                r"^https://(?:[\w-]+\.)?zoom\.us/j/(\d+).*$",
+                "zoommtg://zoom.us/join?action=join&confno=$1",
+            ),
+        ],
+        compiled: OnceLock::new(),
+    },
+    Preset {
+        name: "teams-desktop",
+        version: 1,
+        scheme: "msteams",
+        conversions: &[(r"^https://teams\.microsoft\.com/l/(.+)$", "msteams:/l/$1")],
+        compiled: OnceLock::new(),
+    },
+    Preset {
+        name: "slack-desktop",
+        version: 1,
+        scheme: "slack",
+        conversions: &[(
+            r"^https://app\.slack\.com/client/(T\w+)/([CDG]\w+)/?(?:[?#].*)?$",
+            "slack://channel?team=$1&id=$2",
+        )],
+        compiled: OnceLock::new(),
+    },
+    Preset {
+        name: "figma-desktop",
+        version: 1,
+        scheme: "figma",
+        conversions: &[(
+            r"^https://(?:www\.)?figma\.com/(file|design|proto|board)/(.+)$",
+            "figma://$1/$2",
+        )],
+        compiled: OnceLock::new(),
+    },
+    Preset {
+        name: "spotify-desktop",
+        version: 1,
+        scheme: "spotify",
+        conversions: &[(
+            r"^https://open\.spotify\.com/(?:intl-[\w-]+/)?(track|album|artist|playlist|episode|show)/(\w+).*$",
+            "spotify:$1:$2",
+        )],
+        compiled: OnceLock::new(),
+    },
+];
+
+/// Find a preset by its name, which refers to its latest version, or by `name@version`.
+pub fn find_preset(name: &str) -> Result<&'static Preset> {
+    let (name, version) = match name.split_once('@') {
+        Some((preset_name, version)) => {
+            let version = version
+                .parse()
+                .map_err(|_| Error::InvalidName(name.to_string()))?;
+            (preset_name, Some(version))
+        }
+        None => (name, None),
+    };
+
+    let mut versions = PRESETS.iter().filter(|preset| preset.name == name);
+    match version {
+        Some(version) => versions
+            .find(|preset| preset.version == version)
+            .ok_or_else(|| Error::UnknownVersion(name.to_string(), version)),
+        None => versions
+            .max_by_key(|preset| preset.version)
+            .ok_or_else(|| Error::UnknownPreset(name.to_string())),
+    }
+}
+
+impl Preset {
+    /// Convert one of the app's web links into a link that opens the app, if `url` is one.
+    pub fn convert(&self, url: &str) -> Option<String> {
+        self.compiled_conversions()
+            .iter()
+            .find(|(regex, _)| regex.is_match(url))
+            .map(|(regex, replacement)| regex.replace(url, *replacement).into_owned())
+    }
+
+    /// Compile the regexes of `conversions` once. An invalid one would never match, but the tests make sure there are
+    /// none.
+    fn compiled_conversions(&self) -> &[(Regex, &'static str)] {
+        self.compiled.get_or_init(|| {
+            self.conversions
+                .iter()
+                .filter_map(|(regex, replacement)| Some((Regex::new(regex).ok()?, *replacement)))
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use url::Url;
+
+    fn convert(preset: &str, url: &str) -> Option<String> {
+        find_preset(preset).unwrap().convert(url)
+    }
+
+    #[test]
+    fn regexes_are_valid() {
+        for preset in &PRESETS {
+            for (regex, _) in preset.conversions {
+                assert!(Regex::new(regex).is_ok(), "{}: {}", preset.name, regex);
+            }
+            assert_eq!(
+                preset.compiled_conversions().len(),
+                preset.conversions.len(),
+                "{}",
+                preset.name
+            );
+        }
+    }
+
+    #[test]
+    fn versions_are_unique() {
+        for (index, preset) in PRESETS.iter().enumerate() {
+            assert!(
+                !PRESETS[..index]
+                    .iter()
+                    .any(|other| other.name == preset.name && other.version == preset.version),
+                "{}@{} is declared twice",
+                preset.name,
+                preset.version
+            );
+        }
+    }
+
+    #[test]
+    fn find_by_name_and_version() {
+        assert_eq!(find_preset("zoom-desktop").unwrap().name, "zoom-desktop");
+        assert_eq!(find_preset("zoom-desktop@1").unwrap().version, 1);
+        assert!(matches!(
+            find_preset("zoom-desktop@99"),
+            Err(Error::UnknownVersion(_, 99))
+        ));
+        assert!(matches!(
+            find_preset("zoom-desktop@latest"),
+            Err(Error::InvalidName(_))
+        ));
+        assert!(matches!(
+            find_preset("webex-desktop"),
+            Err(Error::UnknownPreset(_))
+        ));
+    }
+
+    #[test]
+    fn zoom() {
+        assert_eq!(
+            convert(
+                "zoom-desktop",
+                "https://us02web.zoom.us/j/123456789?pwd=aBc.123"
+            )
+            .as_deref(),
+            Some("zoommtg://zoom.us/join?action=join&confno=123456789&pwd=aBc.123")
+        );
+        assert_eq!(
+            convert(
+                "zoom-desktop",
+                "https://zoom.us/j/123456789?from=addon&pwd=xyz"
+            )
+            .as_deref(),
+            Some("zoommtg://zoom.us/join?action=join&confno=123456789&pwd=xyz")
+        );
+        assert_eq!(
+            convert("zoom-desktop", "https://zoom.us/j/123456789").as_deref(),
+            Some("zoommtg://zoom.us/join?action=join&confno=123456789")
+        );
+        assert_eq!(convert("zoom-desktop", "https://zoom.us/pricing"), None);
+        assert_eq!(convert("zoom-desktop", "https://notzoom.us/j/123"), None);
+    }
+
+    #[test]
+    fn teams() {
+        assert_eq!(
+            convert(
+                "teams-desktop",
+                "https://teams.microsoft.com/l/meetup-join/19%3ameeting_abc%40thread.v2/0?context=%7b%7d"
+            )
+            .as_deref(),
+            Some("msteams:/l/meetup-join/19%3ameeting_abc%40thread.v2/0?context=%7b%7d")
+        );
+        assert_eq!(
+            convert("teams-desktop", "https://teams.microsoft.com/"),
+            None
+        );
+    }
+
+    #[test]
+    fn slack() {
+        assert_eq!(
+            convert(
+                "slack-desktop",
+                "https://app.slack.com/client/T0123ABC/C0456DEF"
+            )
+            .as_deref(),
+            Some("slack://channel?team=T0123ABC&id=C0456DEF")
+        );
+        assert_eq!(
+            convert(
+                "slack-desktop",
+                "https://app.slack.com/client/T0123ABC/D0456DEF/"
+            )
+            .as_deref(),
+            Some("slack://channel?team=T0123ABC&id=D0456DEF")
+        );
+        assert_eq!(
+            convert(
+                "slack-desktop",
+                "https://app.slack.com/client/T0123ABC/C0456DEF/thread/C0456DEF-1"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn figma() {
+        assert_eq!(
+            convert(
+                "figma-desktop",
+                "https://www.figma.com/design/AbC123/My-File?node-id=1-2"
+            )
+            .as_deref(),
+            Some("figma://design/AbC123/My-File?node-id=1-2")
+        );
+        assert_eq!(
+            convert("figma-desktop", "https://figma.com/file/AbC123/My-File").as_deref(),
+            Some("figma://file/AbC123/My-File")
+        );
+        assert_eq!(
+            convert("figma-desktop", "https://www.figma.com/pricing/"),
+            None
+        );
+    }
+
+    #[test]
+    fn spotify() {
+        assert_eq!(
+            convert(
+                "spotify-desktop",
+                "https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC?si=abc"
+            )
+            .as_deref(),
+            Some("spotify:track:4uLU6hMCjMI75M1A2tKUQC")
+        );
+        assert_eq!(
+            convert(
+                "spotify-desktop",
+                "https://open.spotify.com/intl-de/album/1DFixLWuPkv3KT3TnV35m3"
+            )
+            .as_deref(),
+            Some("spotify:album:1DFixLWuPkv3KT3TnV35m3")
+        );
+        assert_eq!(
+            convert("spotify-desktop", "https://open.spotify.com/"),
+            None
+        );
+    }
+
+    #[test]
+    fn conversions_use_the_preset_scheme() {
+        let urls = [
+            "https://zoom.us/j/123?pwd=abc",
+            "https://zoom.us/j/123",
+            "https://teams.microsoft.com/l/meetup-join/abc",
+            "https://app.slack.com/client/T1/C1",
+            "https://www.figma.com/file/abc/name",
+            "https://open.spotify.com/track/abc",
+        ];
+        for preset in &PRESETS {
+            for url in urls {
+                if let Some(converted) = preset.convert(url) {
+                    let converted = Url::parse(&converted).unwrap();
+                    assert_eq!(converted.scheme(), preset.scheme, "{}", preset.name);
+                }
+            }
+        }
+    }
+}
@@ -1,7 +1,15 @@
-use crate::config::{Browser, Configuration};
-use anyhow::{bail, Context, Result};
+use crate::chrome_local_state;
+use crate::config::{
+    self, Browser, ChromeProfile, ConfigSource, Configuration, ConfigurationEditor, EdgeProfile,
+    FirefoxOptions, FirefoxProfile, GeckoBrowser, GeckoInstallation, GeckoPreset, RewriteStep,
+    Selection, Template,
+};
+use crate::firefox_profiles;
+use anyhow::{anyhow, bail, Context, Result};
 use const_format::concatcp;
 use log::{debug, error, info, trace, warn};
+use serde::Serialize;
+use similar::TextDiff;
 use simplelog::*;
 use std::{
     fs::{File, OpenOptions},
@@ -48,6 +56,63 @@ fn get_exe_path(exe_name: &str) -> Result<PathBuf> {
     bail!("Could not find path for {}", exe_name);
 }
 
+/// Retrieve an EXE path by looking in the Program Files directories
+fn get_program_files_path(relative_path: &str) -> Result<PathBuf> {
+    for variable in ["ProgramW6432", "ProgramFiles", "ProgramFiles(x86)"] {
+        if let Some(base) = std::env::var_os(variable) {
+            let path = PathBuf::from(base).join(relative_path);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+    }
+
+    bail!("Could not find path for {}", relative_path);
+}
+
+/// The Tor Browser installer defaults to putting the browser on the user's desktop
+fn get_tor_browser_path() -> Option<PathBuf> {
+    std::env::var_os("USERPROFILE").map(|base| PathBuf::from(base).join(r"Desktop\Tor Browser"))
+}
+
+/// Retrieve the EXE path for a Gecko browser. Several of them use `firefox.exe` as their EXE name, so we only
+/// look at App Paths when the name is unique to the browser.
+fn get_gecko_exe_path(preset: GeckoPreset) -> Result<PathBuf> {
+    match preset {
+        GeckoPreset::Firefox => get_exe_path("firefox.exe"),
+        GeckoPreset::FirefoxDeveloperEdition => {
+            get_program_files_path(r"Firefox Developer Edition\firefox.exe")
+        }
+        GeckoPreset::FirefoxNightly => get_program_files_path(r"Firefox Nightly\firefox.exe"),
+        GeckoPreset::LibreWolf => get_exe_path("librewolf.exe")
+            .or_else(|_| get_program_files_path(r"LibreWolf\librewolf.exe")),
+        GeckoPreset::Floorp => get_exe_path("floorp.exe")
+            .or_else(|_| get_program_files_path(r"Ablaze Floorp\floorp.exe")),
+        GeckoPreset::TorBrowser => match get_tor_browser_path() {
+            Some(path) if path.join(r"Browser\firefox.exe").is_file() => {
+                Ok(path.join(r"Browser\firefox.exe"))
+            }
+            _ => bail!("Could not find path for Tor Browser"),
+        },
+    }
+}
+
+fn get_gecko_command(
+    installation: &GeckoInstallation,
+    options: &FirefoxOptions,
+    url: &str,
+) -> Result<(PathBuf, Vec<String>)> {
+    let exe = match installation {
+        GeckoInstallation::Preset { variant } => get_gecko_exe_path(*variant)?,
+        GeckoInstallation::Custom { executable, .. } => executable.clone(),
+    };
+
+    Ok((
+        exe,
+        options.get_arguments(installation.get_data_path(), url)?,
+    ))
+}
+
 /// Register associations with Windows for being a browser
 fn register_urlhandler(extra_args: Option<&str>) -> io::Result<()> {
     // This is used both by initial registration and OS-invoked reinstallation.
@@ -221,12 +286,57 @@ fn get_local_app_data_path() -> Option<PathBuf> {
     None
 }
 
+fn get_roaming_app_data_path() -> Option<PathBuf> {
+    use windows::Storage::UserDataPaths;
+    if let Ok(user_data_paths) = UserDataPaths::GetDefault() {
+        if let Ok(roaming_app_data_path) = user_data_paths.RoamingAppData() {
+            return Some(PathBuf::from(roaming_app_data_path.to_string()));
+        }
+    }
+
+    None
+}
+
+/// Find the path to the system-wide policy file, which lives in the ProgramData folder so that only administrators
+/// can change it
+pub fn get_policy_path() -> Option<PathBuf> {
+    std::env::var_os("ProgramData").map(|base| PathBuf::from(base).join(r"bichrome\policy.json"))
+}
+
+/// Find the path we keep the last config that could be read in, in the user's local app data folder
+fn get_config_cache_path() -> Option<PathBuf> {
+    get_local_app_data_path().map(|base| base.join(r"bichrome\last_good_config.json"))
+}
+
 /// Find the path to Chrome's "Local State" in the user's local app data folder
 pub fn get_chrome_local_state_path() -> Option<PathBuf> {
     let app_data_relative = r"Google\Chrome\User Data\Local State";
     get_local_app_data_path().map(|base| base.join(app_data_relative))
 }
 
+/// Find the path to Edge's "Local State" in the user's local app data folder
+pub fn get_edge_local_state_path() -> Option<PathBuf> {
+    let app_data_relative = r"Microsoft\Edge\User Data\Local State";
+    get_local_app_data_path().map(|base| base.join(app_data_relative))
+}
+
+/// Find the path to a Gecko browser's data directory (containing "profiles.ini"), which is in the user's roaming
+/// app data folder for all but the Tor Browser
+pub fn get_gecko_data_path(preset: GeckoPreset) -> Option<PathBuf> {
+    match preset {
+        GeckoPreset::Firefox
+        | GeckoPreset::FirefoxDeveloperEdition
+        | GeckoPreset::FirefoxNightly => {
+            get_roaming_app_data_path().map(|base| base.join(r"Mozilla\Firefox"))
+        }
+        GeckoPreset::LibreWolf => get_roaming_app_data_path().map(|base| base.join("librewolf")),
+        GeckoPreset::Floorp => get_roaming_app_data_path().map(|base| base.join("Floorp")),
+        GeckoPreset::TorBrowser => {
+            get_tor_browser_path().map(|base| base.join(r"Browser\TorBrowser\Data\Browser"))
+        }
+    }
+}
+
 // This is the definition of our command line options
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -245,6 +355,10 @@ struct CommandOptions {
     #[structopt(long)]
     dry_run: bool,
 
+    /// Use this config file instead of searching for one
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
     /// Choose the mode of operation
     #[structopt(subcommand)]
     mode: Option<ExecutionMode>,
@@ -253,7 +367,7 @@ struct CommandOptions {
     urls: Vec<String>,
 }
 
-#[derive(Debug, Clone, Copy, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 enum ExecutionMode {
     /// Open the given URLs in the correct browser
     Open,
@@ -265,6 +379,117 @@ enum ExecutionMode {
     ShowIcons,
     /// Hide application icons (changes a registry key and nothing else, as we don't have icons)
     HideIcons,
+    /// Check the configuration for errors, like browser profiles that don't exist
+    Check,
+    /// Explain which selector and profiles would be used to open the given URLs
+    Explain,
+    /// Write a JSON schema for the configuration, for validation and autocomplete in editors
+    Schema {
+        /// Where to write the schema, defaults to `bichrome_config.schema.json` next to the configuration
+        #[structopt(parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Generate a starter configuration from the browsers and browser profiles found on this machine
+    Init {
+        /// Overwrite the configuration if there already is one
+        #[structopt(long)]
+        force: bool,
+    },
+    /// List the profiles of all the installed browsers, to help with writing the configuration
+    ListProfiles {
+        /// Print the profiles as JSON rather than as a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Diagnose problems with the installation, the configuration and the browsers it uses
+    Doctor,
+    /// Manage the configuration file
+    Config(ConfigCommand),
+    /// Show which URLs would open with a different profile or command with another configuration
+    Diff {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+        /// A file with the URLs to compare, one per line. Empty lines and lines starting with `#` are ignored.
+        #[structopt(long, parse(from_os_str))]
+        urls: PathBuf,
+    },
+    /// Add, remove or reorder the selectors in the configuration
+    Rules {
+        /// Edit the configuration even if it has comments, which are lost when editing it
+        #[structopt(long)]
+        force: bool,
+        #[structopt(subcommand)]
+        command: RulesCommand,
+    },
+    /// Add or remove profiles in the configuration
+    Profiles {
+        /// Edit the configuration even if it has comments, which are lost when editing it
+        #[structopt(long)]
+        force: bool,
+        #[structopt(subcommand)]
+        command: ProfilesCommand,
+    },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum RulesCommand {
+    /// Add a selector that opens URLs matching a pattern with a profile
+    Add {
+        pattern: String,
+        profile: String,
+        /// Put the selector before the one at this position (starting at 0), rather than after all of them
+        #[structopt(long)]
+        before: Option<usize>,
+    },
+    /// Remove the selectors with a pattern
+    Rm { pattern: String },
+    /// Move the selector with a pattern to another position (starting at 0)
+    Mv { pattern: String, position: usize },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum ProfilesCommand {
+    /// Declare a profile that opens URLs in a browser, like `Chrome`, `Firefox` or `Executable`
+    Add {
+        name: String,
+        browser: String,
+        /// The browser profile to use
+        #[structopt(long)]
+        profile: Option<String>,
+        /// The hosted domain of the Chrome profile to use
+        #[structopt(long)]
+        hosted_domain: Option<String>,
+        /// The Firefox container to open URLs in
+        #[structopt(long)]
+        container: Option<String>,
+        /// Which Gecko browser to use, like `LibreWolf`
+        #[structopt(long)]
+        variant: Option<String>,
+        /// The path of the executable to use
+        #[structopt(long)]
+        path: Option<String>,
+    },
+    /// Remove a profile, which must not be used by any selector or other profile
+    Rm { name: String },
+}
+
+#[derive(Debug, Clone, StructOpt)]
+enum ConfigCommand {
+    /// Show how the configuration would be rewritten to the current version of the format, and rewrite it with
+    /// `--yes`
+    Migrate {
+        /// The configuration file to migrate, defaults to the one bichrome uses
+        #[structopt(parse(from_os_str))]
+        path: Option<PathBuf>,
+        /// Write the migrated configuration, rather than only showing what would change
+        #[structopt(long)]
+        yes: bool,
+        /// Migrate the configuration even if it has comments, which are lost when rewriting it
+        #[structopt(long)]
+        force: bool,
+    },
 }
 
 fn get_exe_relative_path(filename: &str) -> io::Result<PathBuf> {
@@ -283,7 +508,7 @@ fn rotate_and_open_log(log_path: &Path) -> Result<File, io::Error> {
         }
     }
 
-    return OpenOptions::new().append(true).create(true).open(log_path);
+    OpenOptions::new().append(true).create(true).open(log_path)
 }
 
 fn init() -> Result<CommandOptions> {
@@ -320,24 +545,724 @@ fn init() -> Result<CommandOptions> {
     Ok(options)
 }
 
-fn read_config() -> io::Result<Configuration> {
-    let config_path = get_exe_relative_path("bichrome_config.json")?;
-    // We try to read the config, and otherwise just use an empty one instead.
+/// Browsers that we know how to find the profiles of
+#[derive(Debug, Clone, Copy)]
+enum InstalledBrowser {
+    Chrome,
+    Edge,
+    Gecko(GeckoPreset),
+}
+
+impl InstalledBrowser {
+    fn name(&self) -> String {
+        match self {
+            InstalledBrowser::Chrome => "Chrome".to_string(),
+            InstalledBrowser::Edge => "Edge".to_string(),
+            InstalledBrowser::Gecko(preset) => format!("{:?}", preset),
+        }
+    }
+
+    /// Read the profiles of this browser from its "Local State" or "profiles.ini"
+    fn get_profiles(&self) -> Result<Vec<BrowserProfile>> {
+        let local_state_path = match self {
+            InstalledBrowser::Chrome => get_chrome_local_state_path(),
+            InstalledBrowser::Edge => get_edge_local_state_path(),
+            InstalledBrowser::Gecko(preset) => {
+                let data_path = get_gecko_data_path(*preset)
+                    .context("Could not determine the path of profiles.ini")?;
+                let profiles = firefox_profiles::read_profiles_from_dir(data_path)?;
+                return Ok(profiles
+                    .profiles()
+                    .iter()
+                    .map(|profile| BrowserProfile {
+                        browser: self.name(),
+                        directory: profile
+                            .path
+                            .file_name()
+                            .map(|name| name.to_string_lossy().into_owned())
+                            .unwrap_or_default(),
+                        name: Some(profile.name.clone()),
+                        shortcut_name: None,
+                        hosted_domain: None,
+                        account: None,
+                        is_default: profile.is_default,
+                    })
+                    .collect());
+            }
+        };
+
+        let local_state_path =
+            local_state_path.context("Could not determine the path of Local State")?;
+        let profiles = chrome_local_state::read_profiles_from_file(local_state_path)?;
+        Ok(profiles
+            .profiles()
+            .into_iter()
+            .map(|(directory, profile)| BrowserProfile {
+                browser: self.name(),
+                directory: directory.clone(),
+                name: profile.name.clone(),
+                shortcut_name: profile.shortcut_name.clone(),
+                hosted_domain: profile.hosted_domain().map(str::to_string),
+                account: profile.user_name.clone(),
+                is_default: directory == "Default",
+            })
+            .collect())
+    }
+
+    /// The browser configuration that opens URLs in the given browser profile, or in whichever profile the browser
+    /// picks if there's none
+    fn get_browser(&self, profile: Option<&BrowserProfile>) -> Browser {
+        match self {
+            InstalledBrowser::Chrome => Browser::Chrome(match profile {
+                Some(profile) => ChromeProfile::ByName {
+                    name: profile.directory.clone(),
+                },
+                None => ChromeProfile::None {},
+            }),
+            InstalledBrowser::Edge => Browser::Edge(match profile {
+                Some(profile) => EdgeProfile::ByName {
+                    name: profile.directory.clone(),
+                },
+                None => EdgeProfile::None {},
+            }),
+            InstalledBrowser::Gecko(preset) => {
+                let options = FirefoxOptions {
+                    profile: match profile {
+                        Some(profile) => FirefoxProfile::ByName {
+                            name: profile.display_name().to_string(),
+                        },
+                        None => FirefoxProfile::None {},
+                    },
+                    container: None,
+                };
+                if *preset == GeckoPreset::Firefox {
+                    Browser::Firefox(options)
+                } else {
+                    Browser::Gecko(GeckoBrowser {
+                        installation: GeckoInstallation::Preset { variant: *preset },
+                        options,
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Find the browsers installed on this machine that we know how to find the profiles of
+fn get_installed_browsers() -> Vec<InstalledBrowser> {
+    let mut browsers = Vec::new();
+    if get_exe_path("chrome.exe").is_ok() {
+        browsers.push(InstalledBrowser::Chrome);
+    }
+    if get_exe_path("msedge.exe").is_ok() {
+        browsers.push(InstalledBrowser::Edge);
+    }
+    for preset in GeckoPreset::ALL {
+        if get_gecko_exe_path(preset).is_ok() {
+            browsers.push(InstalledBrowser::Gecko(preset));
+        }
+    }
+    browsers
+}
+
+/// Read the profiles of every installed browser. Firefox, Developer Edition and Nightly share a single profiles.ini,
+/// so its profiles are only listed for the first of them that's installed, and the others get none. Otherwise
+/// Developer Edition would get the release channel's profiles too, which it refuses to open.
+fn get_installed_profiles() -> Vec<(InstalledBrowser, Result<Vec<BrowserProfile>>)> {
+    let mut read_data_paths = Vec::new();
+    get_installed_browsers()
+        .into_iter()
+        .map(|browser| {
+            if let InstalledBrowser::Gecko(preset) = browser {
+                let data_path = get_gecko_data_path(preset);
+                if data_path.is_some() && read_data_paths.contains(&data_path) {
+                    return (browser, Ok(Vec::new()));
+                }
+                read_data_paths.push(data_path);
+            }
+            let profiles = browser.get_profiles();
+            (browser, profiles)
+        })
+        .collect()
+}
+
+/// A browser profile found on this machine
+#[derive(Debug, Serialize)]
+struct BrowserProfile {
+    browser: String,
+    /// Name of the profile's directory
+    directory: String,
+    name: Option<String>,
+    shortcut_name: Option<String>,
+    hosted_domain: Option<String>,
+    /// The account the profile is signed in to
+    account: Option<String>,
+    is_default: bool,
+}
+
+impl BrowserProfile {
+    fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.directory)
+    }
+}
+
+/// Generate a starter configuration with a profile for every browser profile we can find. The default profile is
+/// the default browser profile of the first browser we find.
+fn discover_profiles() -> Template {
+    let mut template = Template::default();
+    for (browser, profiles) in get_installed_profiles() {
+        match profiles {
+            Ok(profiles) if !profiles.is_empty() => {
+                for profile in &profiles {
+                    template.add_profile(
+                        &format!("{} {}", browser.name(), profile.display_name()),
+                        browser.get_browser(Some(profile)),
+                        profile.is_default,
+                    );
+                }
+            }
+            profiles => {
+                if let Err(error) = profiles {
+                    warn!("could not read {} profiles: {:#}", browser.name(), error);
+                }
+                template.add_profile(&browser.name(), browser.get_browser(None), true);
+            }
+        }
+    }
+
+    template.add_profile("OS Default", Browser::OsDefault, true);
+    template
+}
+
+/// Print the profiles of all the installed browsers as a table
+fn print_profile_table(profiles: &[BrowserProfile]) {
+    let header = [
+        "Browser",
+        "Directory",
+        "Name",
+        "Shortcut name",
+        "Hosted domain",
+        "Account",
+        "Default",
+    ];
+    let rows: Vec<[String; 7]> = profiles
+        .iter()
+        .map(|profile| {
+            [
+                profile.browser.clone(),
+                profile.directory.clone(),
+                profile.name.clone().unwrap_or_default(),
+                profile.shortcut_name.clone().unwrap_or_default(),
+                profile.hosted_domain.clone().unwrap_or_default(),
+                profile.account.clone().unwrap_or_default(),
+                if profile.is_default { "yes" } else { "" }.to_string(),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let print_row = |row: &[&str]| {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+    print_row(&header);
+    for row in &rows {
+        let row: Vec<&str> = row.iter().map(String::as_str).collect();
+        print_row(&row);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DiagnosisStatus {
+    Pass,
+    /// Something that might be a problem, depending on the configuration
+    Warn,
+    Fail,
+}
+
+/// The outcome of one of the checks done by `doctor`
+struct Diagnosis {
+    status: DiagnosisStatus,
+    description: String,
+    details: String,
+    /// How to fix the problem, if the check didn't pass
+    remedy: &'static str,
+}
+
+impl Diagnosis {
+    fn new(description: impl Into<String>, result: Result<String>, remedy: &'static str) -> Self {
+        let (status, details) = match result {
+            Ok(details) => (DiagnosisStatus::Pass, details),
+            Err(error) => (DiagnosisStatus::Fail, format!("{:#}", error)),
+        };
+        Diagnosis {
+            status,
+            description: description.into(),
+            details,
+            remedy,
+        }
+    }
+
+    /// Downgrade a failure to a warning, for things that are only a problem with some configurations
+    fn optional(mut self) -> Self {
+        if self.status == DiagnosisStatus::Fail {
+            self.status = DiagnosisStatus::Warn;
+        }
+        self
+    }
+}
+
+/// Check that the ProgID we register points at this executable
+fn check_registration() -> Result<String> {
+    let exe_path = std::env::current_exe()?;
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let command: String = hkcu
+        .open_subkey(format!(r"{}\shell\open\command", PROGID_PATH))
+        .and_then(|command| command.get_value(""))
+        .context("bichrome is not registered")?;
+    hkcu.open_subkey(r"SOFTWARE\RegisteredApplications")
+        .and_then(|applications| applications.get_value::<String, _>(DISPLAY_NAME))
+        .context("bichrome is not in the list of registered applications")?;
+
+    if command.contains(&*exe_path.to_string_lossy()) {
+        Ok(command)
+    } else {
+        bail!("registered to launch a different executable: {}", command)
+    }
+}
+
+/// Check that the user picked us as the handler for the given URL protocol
+fn check_default_handler(protocol: &str) -> Result<String> {
+    let user_choice_path = format!(
+        r"SOFTWARE\Microsoft\Windows\Shell\Associations\UrlAssociations\{}\UserChoice",
+        protocol
+    );
+    let prog_id: String = RegKey::predef(HKEY_CURRENT_USER)
+        .open_subkey(user_choice_path)
+        .and_then(|user_choice| user_choice.get_value("ProgId"))
+        .context("no default handler has been picked")?;
+    if prog_id == PROGID {
+        Ok(prog_id)
+    } else {
+        bail!("the default handler is {}", prog_id)
+    }
+}
+
+/// Run all the checks for `doctor`, from registration to the configuration and the browsers it uses.
+fn diagnose(config_override: Option<&Path>) -> Vec<Diagnosis> {
+    let mut diagnoses = vec![Diagnosis::new(
+        "bichrome is registered as a browser",
+        check_registration(),
+        "run `bichrome-win64.exe register` from the location you want to keep bichrome in",
+    )];
+    for protocol in ["http", "https"] {
+        diagnoses.push(Diagnosis::new(
+            format!("bichrome is the default handler for {} URLs", protocol),
+            check_default_handler(protocol),
+            "open \"Default Apps\" and pick bichrome as your web browser",
+        ));
+    }
+
+    diagnoses.push(Diagnosis::new(
+        "log file is writable",
+        get_exe_relative_path("bichrome.log")
+            .and_then(|log_path| {
+                OpenOptions::new().append(true).open(&log_path)?;
+                Ok(log_path.display().to_string())
+            })
+            .map_err(anyhow::Error::from),
+        "move bichrome to a directory you can write to, like %localappdata%\\Programs\\bichrome",
+    ));
+
+    let config_path = locate_config(config_override).map_err(anyhow::Error::from);
+    let config = config_path.and_then(|(config_path, source)| {
+        if !config_path.is_file() {
+            if source == ConfigSource::SearchPath {
+                let search_path = get_config_search_path()?;
+                bail!(
+                    "there is no config in any of {}",
+                    search_path
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+            bail!(
+                "there is no config at {} ({})",
+                config_path.display(),
+                source
+            );
+        }
+        Configuration::read_from_file(&config_path)
+            .with_context(|| format!("could not read config at {}", config_path.display()))
+            .map(|config| (config_path, source, config))
+    });
+    match config {
+        Ok((config_path, source, config)) => {
+            diagnoses.push(Diagnosis::new(
+                "config can be read",
+                Ok(format!(
+                    "{} ({}) with {} profile(s) and {} selector(s)",
+                    config_path.display(),
+                    source,
+                    config.profiles.len(),
+                    config.profile_selection.len()
+                )),
+                "",
+            ));
+            for error in config.check() {
+                diagnoses.push(Diagnosis::new(
+                    "config problem",
+                    Err(error.into()),
+                    "run `bichrome-win64.exe list-profiles` to see which browser profiles exist",
+                ));
+            }
+        }
+        Err(error) => diagnoses.push(Diagnosis::new(
+            "config can be read",
+            Err(error),
+            "create a config with `bichrome-win64.exe init`, or fix the error in it",
+        )),
+    }
+
+    if let Some(policy_path) = get_policy_path().filter(|path| path.is_file()) {
+        diagnoses.push(Diagnosis::new(
+            "policy can be read",
+            Configuration::read_policy()
+                .map(|_| policy_path.display().to_string())
+                .map_err(anyhow::Error::from),
+            "ask your administrator to fix the policy file",
+        ));
+    }
+
+    let browser_exes = [("Chrome", "chrome.exe"), ("Edge", "msedge.exe")]
+        .map(|(name, exe_name)| (name.to_string(), get_exe_path(exe_name)));
+    let gecko_exes =
+        GeckoPreset::ALL.map(|preset| (format!("{:?}", preset), get_gecko_exe_path(preset)));
+    for (name, exe_path) in browser_exes.into_iter().chain(gecko_exes) {
+        diagnoses.push(
+            Diagnosis::new(
+                format!("{} is installed", name),
+                exe_path.map(|exe_path| exe_path.display().to_string()),
+                "only needed if your config uses this browser",
+            )
+            .optional(),
+        );
+    }
+
+    for browser in get_installed_browsers() {
+        diagnoses.push(Diagnosis::new(
+            format!("{} profiles can be read", browser.name()),
+            browser
+                .get_profiles()
+                .map(|profiles| format!("found {} profile(s)", profiles.len())),
+            "open the browser once to create its profile, and make sure bichrome runs as the same user",
+        ));
+    }
+
+    diagnoses
+}
+
+/// Release builds use the Windows subsystem and don't get a console, so attach to the console of whoever launched us
+/// to be able to print the output of commands like `list-profiles`.
+fn attach_console() {
+    if !cfg!(debug_assertions) {
+        use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+        unsafe {
+            // This fails if we weren't launched from a console, in which case there's nowhere to print to anyway.
+            let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+        }
+    }
+}
+
+/// The directories we look for the config in, in order: next to the executable, `%APPDATA%\bichrome`, then the
+/// XDG config directory.
+fn get_config_search_path() -> io::Result<Vec<PathBuf>> {
+    let exe_path = std::env::current_exe()?;
+    let mut search_path = vec![exe_path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .to_path_buf()];
+    search_path.extend(get_roaming_app_data_path().map(|path| path.join("bichrome")));
+    search_path.extend(config::get_xdg_config_path());
+    Ok(search_path)
+}
+
+fn locate_config(config_override: Option<&Path>) -> io::Result<(PathBuf, ConfigSource)> {
+    let search_path = get_config_search_path()?;
+    Ok(config::locate_config_file(config_override, &search_path)
+        .expect("search path always includes the executable's directory"))
+}
+
+fn get_config_path(config_override: Option<&Path>) -> io::Result<PathBuf> {
+    locate_config(config_override).map(|(path, _)| path)
+}
+
+/// Apply `edit` to the config at `config_path`, and write it back unless it's a dry run. The changes are logged as a
+/// diff.
+fn edit_config(
+    config_path: &Path,
+    dry_run: bool,
+    force: bool,
+    edit: impl FnOnce(&mut ConfigurationEditor) -> Result<(), config::Error>,
+) -> Result<()> {
+    let mut editor = ConfigurationEditor::open(config_path, force)
+        .with_context(|| format!("Failed to read {}", config_path.display()))?;
+    edit(&mut editor)?;
+    let edited = editor.finish()?;
+
+    let diff = TextDiff::from_lines(&edited.original, &edited.edited);
+    let header = config_path.display().to_string();
+    info!(
+        "editing {}:\n{}",
+        config_path.display(),
+        diff.unified_diff().header(&header, &header)
+    );
+    if dry_run {
+        info!("(dry-run) would write edited config");
+    } else {
+        std::fs::write(config_path, edited.edited).context("Failed to write config")?;
+        info!("wrote edited config to {}", config_path.display());
+    }
+    Ok(())
+}
+
+fn read_config(config_override: Option<&Path>) -> Result<Configuration> {
+    let config_path = get_config_path(config_override)?;
+    // If the config is broken, this falls back to the last one that could be read, or to the policy alone.
     debug!("attempting to load config from {}", config_path.display());
-    let config = Configuration::read_from_file(&config_path);
-    Ok(match config {
-        Ok(config) => {
-            trace!("config: {:#?}", config);
-            config
+    let config =
+        Configuration::read_with_fallback(&config_path, get_config_cache_path().as_deref())?;
+    trace!("config: {:#?}", config);
+    Ok(config)
+}
+
+/// Figure out the executable and arguments needed to open `url` in `browser`
+fn get_browser_command(browser: &Browser, url: &str) -> Result<(PathBuf, Vec<String>)> {
+    Ok(match browser {
+        Browser::Chrome(profile) => {
+            let mut args = Vec::new();
+            if let Some(argument) = profile.get_argument()? {
+                args.push(argument);
+            }
+            args.push(url.to_string());
+
+            (get_exe_path("chrome.exe")?, args)
         }
-        Err(e) => {
-            error!("failed to parse config: {:?}", e);
-            warn!("opening URLs without profile");
-            Configuration::empty()
+        Browser::Firefox(options) => get_gecko_command(
+            &GeckoInstallation::Preset {
+                variant: GeckoPreset::Firefox,
+            },
+            options,
+            url,
+        )?,
+        Browser::Gecko(gecko) => get_gecko_command(&gecko.installation, &gecko.options, url)?,
+        Browser::OsDefault => (get_exe_path("msedge.exe")?, vec![url.to_string()]),
+        Browser::Edge(profile) => {
+            let mut args = Vec::new();
+            if let Some(argument) = profile.get_argument()? {
+                args.push(argument);
+            }
+            args.push(url.to_string());
+
+            (get_exe_path("msedge.exe")?, args)
+        }
+        Browser::Safari => {
+            bail!("Apple Safari not supported on Windows")
+        }
+        Browser::Executable(location) => (location.get_path(), vec![url.to_string()]),
+        Browser::SystemHandler => {
+            bail!("the system handler is opened through ShellExecute, not a command")
         }
     })
 }
 
+/// Quote `exe` and `args` into a command line, for logging and dry runs.
+fn format_command_line(exe: &Path, args: &[String]) -> String {
+    format!("\"{}\" \"{}\"", exe.display(), args.join("\" \""))
+}
+
+/// Describe the profile and command that `config` would open `url` with, for comparing configs.
+fn describe_route(config: &Configuration, url: &str) -> String {
+    let candidates = config
+        .prepare_url(url)
+        .and_then(|url| config.choose_browser(&url));
+    let candidates = match candidates {
+        Ok(candidates) => candidates,
+        Err(error) => return format!("error: {:#}", anyhow::Error::new(error)),
+    };
+    let Some(candidate) = candidates.first() else {
+        return "no profile is allowed by the policy".to_string();
+    };
+    if let Browser::SystemHandler = candidate.browser {
+        return format!(
+            "'{}': system handler for {}",
+            candidate.profile_name, candidate.url
+        );
+    }
+    match get_browser_command(&candidate.browser, &candidate.url) {
+        Ok((exe, args)) => format!(
+            "'{}': {}",
+            candidate.profile_name,
+            format_command_line(&exe, &args)
+        ),
+        Err(error) => format!("'{}': error: {:#}", candidate.profile_name, error),
+    }
+}
+
+/// Allow any process to steal focus from us, so that we will transfer focus "nicely" to the browser.
+fn allow_set_foreground_window() {
+    use windows::Win32::UI::WindowsAndMessaging::{AllowSetForegroundWindow, ASFW_ANY};
+    unsafe {
+        if let Err(error) = AllowSetForegroundWindow(ASFW_ANY) {
+            warn!("Could not `AllowSetForegroundWindow`: {error:?}");
+        }
+    }
+}
+
+/// Find the program registered to open URLs with `scheme`.
+fn get_scheme_handler(scheme: &str) -> Result<PathBuf> {
+    use windows::core::{HSTRING, PCWSTR, PWSTR};
+    use windows::Win32::UI::Shell::{AssocQueryStringW, ASSOCF_IS_PROTOCOL, ASSOCSTR_EXECUTABLE};
+
+    let scheme = HSTRING::from(scheme);
+    let query = |buffer: PWSTR, length: &mut u32| unsafe {
+        AssocQueryStringW(
+            ASSOCF_IS_PROTOCOL,
+            ASSOCSTR_EXECUTABLE,
+            &scheme,
+            PCWSTR::null(),
+            buffer,
+            length,
+        )
+        .ok()
+    };
+
+    // The first call only gets the length of the path, including the terminating null.
+    let mut length = 0;
+    query(PWSTR::null(), &mut length)?;
+    let mut buffer = vec![0u16; length as usize];
+    query(PWSTR(buffer.as_mut_ptr()), &mut length)?;
+    buffer.truncate(length.saturating_sub(1) as usize);
+    Ok(PathBuf::from(String::from_utf16_lossy(&buffer)))
+}
+
+/// Whether `handler` is the bichrome at `exe_path`. Paths that can't be canonicalized, e.g. because the handler was
+/// removed, are compared as they are, so that a handler registered with the same path still counts as bichrome.
+fn is_bichrome(handler: &Path, exe_path: &Path) -> bool {
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    handler == exe_path || canonical(handler) == canonical(exe_path)
+}
+
+/// Hand `url` to whichever program is registered for its scheme, unless that's bichrome itself, which would just
+/// hand it back to us. `get_handler` finds the program registered for a scheme, like `get_scheme_handler`.
+fn open_with_system_handler(
+    url: &str,
+    dry_run: bool,
+    get_handler: impl FnOnce(&str) -> Result<PathBuf>,
+) -> Result<()> {
+    use windows::core::{w, HSTRING, PCWSTR};
+    use windows::Win32::{
+        Foundation::HWND,
+        UI::{Shell::ShellExecuteW, WindowsAndMessaging::SW_SHOWNORMAL},
+    };
+
+    let scheme = url.split_once(':').map_or(url, |(scheme, _)| scheme);
+    // The file types bichrome registers for would be handed back to us, and which ones a file: URL opens with
+    // depends on its extension rather than its scheme.
+    if scheme.eq_ignore_ascii_case("file") {
+        bail!("bichrome is registered for files too, so it can't hand off file: URLs");
+    }
+    let handler = get_handler(scheme)
+        .with_context(|| format!("no program is registered for {}: URLs", scheme))?;
+    debug!("{}: URLs are handled by {}", scheme, handler.display());
+    if is_bichrome(&handler, &std::env::current_exe()?) {
+        bail!(
+            "bichrome is the registered handler for {}: URLs, so it can't hand them off",
+            scheme
+        );
+    }
+
+    if dry_run {
+        info!("(dry-run) would open {} with the system handler", url);
+        return Ok(());
+    }
+
+    allow_set_foreground_window();
+    debug!("opening {} with the system handler", url);
+    let result = unsafe {
+        ShellExecuteW(
+            HWND::default(),
+            w!("open"),
+            &HSTRING::from(url),
+            PCWSTR::null(),
+            PCWSTR::null(),
+            SW_SHOWNORMAL,
+        )
+    };
+    // ShellExecute returns a value greater than 32 on success, and an error code otherwise.
+    if result.0 <= 32 {
+        return Err(anyhow!("ShellExecute failed with error {}", result.0)
+            .context("no program is registered for the URL"));
+    }
+    Ok(())
+}
+
+/// Launch `browser` to open `url`, returning an error describing why if we can't.
+fn open_url(browser: &Browser, url: &str, dry_run: bool) -> Result<()> {
+    if let Browser::SystemHandler = browser {
+        return open_with_system_handler(url, dry_run, get_scheme_handler);
+    }
+
+    let (exe, args) = get_browser_command(browser, url).map_err(|error| {
+        // Errors from the configuration are from resolving browser profiles, anything else means we
+        // couldn't find the browser.
+        if error.is::<config::Error>() {
+            error.context("profile not found")
+        } else {
+            error.context("browser not installed")
+        }
+    })?;
+
+    let commandline = format_command_line(&exe, &args);
+    if dry_run {
+        info!("(dry-run) {}", commandline);
+    } else {
+        allow_set_foreground_window();
+
+        // Let's not log the URL to the logs by default, so there's not a gross log file
+        // the user might not be aware of inadvertently 'tracking' their browsing activity.
+        info!("picked {:?}", browser);
+        debug!("launching {}", commandline);
+        Command::new(&exe)
+            .stdout(Stdio::null())
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .args(args)
+            .spawn()
+            .with_context(|| {
+                format!(
+                    "Failed to launch browser {:?} for URL {}, attempted command {}",
+                    browser, url, commandline
+                )
+            })
+            .context("launch failed")?;
+    }
+
+    Ok(())
+}
+
 pub fn main() -> Result<()> {
     let options = init()?;
 
@@ -347,7 +1272,7 @@ pub fn main() -> Result<()> {
         ExecutionMode::Open
     });
 
-    if !matches!(mode, ExecutionMode::Open) && !options.urls.is_empty() {
+    if !matches!(mode, ExecutionMode::Open | ExecutionMode::Explain) && !options.urls.is_empty() {
         bail!(
             "Specified a list of URLs for mode {:?} which doesn't take URLs",
             mode
@@ -396,69 +1321,329 @@ pub fn main() -> Result<()> {
                 hide_icons().context("Failed to hide icons")?;
             }
         }
-        ExecutionMode::Open => {
-            let config = read_config()?;
+        ExecutionMode::Check => {
+            let config_path = get_config_path(options.config.as_deref())?;
+            info!("checking config at {}", config_path.display());
+            let config =
+                Configuration::read_from_file(&config_path).context("Failed to read config")?;
 
-            for url in options.urls {
-                let browser = config.choose_browser(&url)?;
-                let (exe, args) = match &browser {
-                    Browser::Chrome(profile) => {
-                        let mut args = Vec::new();
-                        if let Some(argument) = profile.get_argument()? {
-                            args.push(argument);
-                        }
-                        args.push(url.to_string());
+            let errors = config.check();
+            if errors.is_empty() {
+                info!("no problems found in config");
+            } else {
+                let error_count = errors.len();
+                for error in errors {
+                    error!("{:#}", anyhow::Error::new(error));
+                }
+                bail!("Found {} problem(s) in config", error_count);
+            }
+        }
+        ExecutionMode::Schema { output } => {
+            let output = match output {
+                Some(output) => output,
+                None => get_config_path(options.config.as_deref())?
+                    .with_file_name("bichrome_config.schema.json"),
+            };
+            if options.dry_run {
+                info!("(dry-run) would write schema to {}", output.display());
+            } else {
+                let schema = serde_json::to_string_pretty(&config::json_schema())?;
+                std::fs::write(&output, schema)
+                    .with_context(|| format!("Failed to write schema to {}", output.display()))?;
+                info!("wrote schema to {}", output.display());
+            }
+        }
+        ExecutionMode::Init { force } => {
+            let (existing_config_path, source) = locate_config(options.config.as_deref())?;
+            if existing_config_path.is_file() && !force {
+                bail!(
+                    "{} already exists, use --force to overwrite it",
+                    existing_config_path.display()
+                );
+            }
 
-                        (get_exe_path("chrome.exe")?, args)
-                    }
-                    Browser::Firefox => (get_exe_path("firefox.exe")?, vec![url.to_string()]),
-                    Browser::OsDefault => (get_exe_path("msedge.exe")?, vec![url.to_string()]),
-                    Browser::Edge(profile) => {
-                        let mut args = Vec::new();
-                        if let Some(argument) = profile.get_argument()? {
-                            args.push(argument);
-                        }
-                        args.push(url.to_string());
+            // The generated config is JSON, so when we found a config in another format we replace it with a JSON
+            // one, which takes precedence.
+            let config_path = match source {
+                ConfigSource::SearchPath => {
+                    existing_config_path.with_file_name("bichrome_config.json")
+                }
+                _ => existing_config_path,
+            };
+            let config = discover_profiles().to_jsonc()?;
+            if options.dry_run {
+                info!(
+                    "(dry-run) would write config to {}:\n{}",
+                    config_path.display(),
+                    config
+                );
+            } else {
+                std::fs::write(&config_path, config).context("Failed to write config")?;
+                info!("wrote config to {}", config_path.display());
+            }
+        }
+        ExecutionMode::Doctor => {
+            let diagnoses = diagnose(options.config.as_deref());
+            attach_console();
+            for diagnosis in &diagnoses {
+                let status = match diagnosis.status {
+                    DiagnosisStatus::Pass => "PASS",
+                    DiagnosisStatus::Warn => "WARN",
+                    DiagnosisStatus::Fail => "FAIL",
+                };
+                println!(
+                    "[{}] {}: {}",
+                    status, diagnosis.description, diagnosis.details
+                );
+                if diagnosis.status != DiagnosisStatus::Pass {
+                    println!("       hint: {}", diagnosis.remedy);
+                }
+            }
 
-                        (get_exe_path("msedge.exe")?, args)
+            let failures = diagnoses
+                .iter()
+                .filter(|diagnosis| diagnosis.status == DiagnosisStatus::Fail)
+                .count();
+            if failures > 0 {
+                bail!("Found {} problem(s)", failures);
+            }
+            info!("no problems found");
+        }
+        ExecutionMode::ListProfiles { json } => {
+            let mut profiles = Vec::new();
+            for (browser, browser_profiles) in get_installed_profiles() {
+                match browser_profiles {
+                    Ok(browser_profiles) => profiles.extend(browser_profiles),
+                    Err(error) => warn!("could not read {} profiles: {:#}", browser.name(), error),
+                }
+            }
+
+            attach_console();
+            if json {
+                println!("{}", serde_json::to_string_pretty(&profiles)?);
+            } else {
+                print_profile_table(&profiles);
+            }
+        }
+        ExecutionMode::Diff { old, new, urls } => {
+            let read = |path: &Path| {
+                Configuration::read_from_file(path)
+                    .with_context(|| format!("Failed to read config at {}", path.display()))
+            };
+            let (old_config, new_config) = (read(&old)?, read(&new)?);
+            let urls = std::fs::read_to_string(&urls)
+                .with_context(|| format!("Failed to read URLs from {}", urls.display()))?;
+            let urls: Vec<&str> = urls
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .collect();
+
+            attach_console();
+            let mut changed = 0;
+            for url in &urls {
+                let old_route = describe_route(&old_config, url);
+                let new_route = describe_route(&new_config, url);
+                if old_route != new_route {
+                    changed += 1;
+                    println!("{}", url);
+                    println!("  old: {}", old_route);
+                    println!("  new: {}", new_route);
+                }
+            }
+            println!(
+                "{} of {} URL(s) would open differently",
+                changed,
+                urls.len()
+            );
+        }
+        ExecutionMode::Rules { force, command } => {
+            let config_path = get_config_path(options.config.as_deref())?;
+            edit_config(
+                &config_path,
+                options.dry_run,
+                force,
+                |editor| match command {
+                    RulesCommand::Add {
+                        pattern,
+                        profile,
+                        before,
+                    } => editor.add_rule(&pattern, &profile, before),
+                    RulesCommand::Rm { pattern } => editor.remove_rule(&pattern),
+                    RulesCommand::Mv { pattern, position } => editor.move_rule(&pattern, position),
+                },
+            )?;
+        }
+        ExecutionMode::Profiles { force, command } => {
+            let config_path = get_config_path(options.config.as_deref())?;
+            edit_config(
+                &config_path,
+                options.dry_run,
+                force,
+                |editor| match command {
+                    ProfilesCommand::Add {
+                        name,
+                        browser,
+                        profile,
+                        hosted_domain,
+                        container,
+                        variant,
+                        path,
+                    } => {
+                        let mut declaration = serde_json::Map::new();
+                        declaration.insert("browser".to_string(), browser.into());
+                        let settings = [
+                            ("profile", profile),
+                            ("hosted_domain", hosted_domain),
+                            ("container", container),
+                            ("variant", variant),
+                            ("path", path),
+                        ];
+                        for (key, value) in settings {
+                            if let Some(value) = value {
+                                declaration.insert(key.to_string(), value.into());
+                            }
+                        }
+                        editor.add_profile(&name, declaration)
                     }
-                    Browser::Safari => {
-                        bail!("Apple Safari not supported on Windows")
+                    ProfilesCommand::Rm { name } => editor.remove_profile(&name),
+                },
+            )?;
+        }
+        ExecutionMode::Config(ConfigCommand::Migrate { path, yes, force }) => {
+            let path = match path {
+                Some(path) => path,
+                None => get_config_path(options.config.as_deref())?,
+            };
+            let migration = config::migrate_file(&path, force)
+                .with_context(|| format!("Failed to migrate {}", path.display()))?;
+            let Some(migration) = migration else {
+                info!(
+                    "{} is already at version {}",
+                    path.display(),
+                    config::CONFIG_VERSION
+                );
+                return Ok(());
+            };
+
+            let diff = TextDiff::from_lines(&migration.original, &migration.migrated);
+            let header = path.display().to_string();
+            attach_console();
+            println!(
+                "migrating {} from version {} to {}:\n{}",
+                path.display(),
+                migration.from_version,
+                config::CONFIG_VERSION,
+                diff.unified_diff().header(&header, &header)
+            );
+
+            if options.dry_run {
+                info!("(dry-run) would write migrated config");
+            } else if !yes {
+                println!("run `config migrate --yes` to write these changes");
+            } else {
+                let mut backup_path = path.clone().into_os_string();
+                backup_path.push(".bak");
+                std::fs::copy(&path, &backup_path).context("Failed to back up config")?;
+                std::fs::write(&path, migration.migrated).context("Failed to write config")?;
+                info!(
+                    "wrote migrated config, the original was saved to {}",
+                    Path::new(&backup_path).display()
+                );
+            }
+        }
+        ExecutionMode::Explain => {
+            let (config_path, source) = locate_config(options.config.as_deref())?;
+            info!("using config at {} ({})", config_path.display(), source);
+            let config =
+                Configuration::read_from_file(&config_path).context("Failed to read config")?;
+
+            for url in &options.urls {
+                let prepared_url = config.prepare_url(url)?;
+                let mut previous_url = url.as_str();
+                for step in &prepared_url.steps {
+                    match step {
+                        RewriteStep::Unwrapped { url } => {
+                            info!("{} redirects to {}", previous_url, url)
+                        }
+                        RewriteStep::Rewritten { rewrite, url } => info!(
+                            "{} was rewritten to {} by rewrite #{}",
+                            previous_url, url, rewrite
+                        ),
+                        RewriteStep::Preset { preset, url } => info!(
+                            "{} was converted to {} by preset '{}'",
+                            previous_url, url, preset
+                        ),
                     }
-                    Browser::Executable(location) => (location.get_path(), vec![url.to_string()])
-                };
+                    previous_url = step.url();
+                }
+                let url = &prepared_url.url;
 
-                let commandline = format!("\"{}\" \"{}\"", exe.display(), args.join("\" \""));
-                if options.dry_run {
-                    info!("(dry-run) {}", commandline);
-                } else {
-                    // Allow any process to steal focus from us, so that we will transfer focus "nicely" to
-                    // the browser.
-                    use windows::Win32::UI::WindowsAndMessaging::{
-                        AllowSetForegroundWindow, ASFW_ANY,
-                    };
-                    unsafe {
-                        if let Err(error) = AllowSetForegroundWindow(ASFW_ANY) {
-                            warn!("Could not `AllowSetForegroundWindow`: {error:?}");
+                match config.select_profile(&prepared_url)? {
+                    Selection::Selector(index, selector) => info!(
+                        "{} matched selector #{} with pattern {} from {}",
+                        url,
+                        index,
+                        selector.pattern,
+                        selector
+                            .source
+                            .as_deref()
+                            .unwrap_or(&config_path)
+                            .display()
+                    ),
+                    Selection::DefaultProfile(profile_name) => info!(
+                        "{} did not match any selector, using default_profile '{}'",
+                        url, profile_name
+                    ),
+                    Selection::ImplicitDefault => info!(
+                        "{} did not match any selector and there's no default_profile, using Chrome",
+                        url
+                    ),
+                    Selection::Preset(preset) => info!(
+                        "{} is opened by the system handler, as converted by preset '{}'",
+                        url, preset
+                    ),
+                    Selection::Forbidden => info!(
+                        "{} did not match any selector the policy allows, and the policy forbids the default",
+                        url
+                    ),
+                }
+
+                for candidate in config.choose_browser(&prepared_url)? {
+                    info!(
+                        "  would try profile '{}' with {}: {:?}",
+                        candidate.profile_name, candidate.url, candidate.browser
+                    );
+                }
+            }
+        }
+        ExecutionMode::Open => {
+            let config = read_config(options.config.as_deref())?;
+
+            for url in options.urls {
+                let prepared_url = config.prepare_url(&url)?;
+                let candidates = config.choose_browser(&prepared_url)?;
+                let mut opened = false;
+                for candidate in &candidates {
+                    match open_url(&candidate.browser, &candidate.url, options.dry_run) {
+                        Ok(()) => {
+                            opened = true;
+                            break;
+                        }
+                        Err(error) => {
+                            warn!("skipping profile '{}': {:#}", candidate.profile_name, error)
                         }
                     }
+                }
 
-                    // Let's not log the URL to the logs by default, so there's not a gross log file
-                    // the user might not be aware of inadvertently 'tracking' their browsing activity.
-                    info!("picked {:?}", &browser);
-                    debug!("launching {}", commandline);
-                    Command::new(&exe)
-                        .stdout(Stdio::null())
-                        .stdin(Stdio::null())
-                        .stderr(Stdio::null())
-                        .args(args)
-                        .spawn()
-                        .with_context(|| {
-                            format!(
-                                "Failed to launch browser {:?} for URL {}, attempted command {}",
-                                &browser, url, commandline
-                            )
-                        })?;
+                if !opened {
+                    bail!(
+                        "Could not open URL with any of the profiles {:?}",
+                        candidates
+                            .iter()
+                            .map(|candidate| &candidate.profile_name)
+                            .collect::<Vec<_>>()
+                    );
                 }
             }
         }
@@ -466,3 +1651,56 @@ pub fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_handler_is_bichrome() {
+        // The test binary stands in for bichrome.
+        let error = open_with_system_handler("https://example.com/", true, |_| {
+            Ok(std::env::current_exe()?)
+        })
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("bichrome is the registered handler"));
+    }
+
+    #[test]
+    fn system_handler_is_another_program() {
+        let handler = |scheme: &str| {
+            assert_eq!(scheme, "zoommtg");
+            Ok(std::env::temp_dir().join("zoom.exe"))
+        };
+        assert!(
+            open_with_system_handler("zoommtg://zoom.us/join?confno=123", true, handler).is_ok()
+        );
+    }
+
+    #[test]
+    fn system_handler_is_bichrome_at_a_missing_path() {
+        let path = std::env::temp_dir()
+            .join("bichrome-missing")
+            .join("bichrome.exe");
+        assert!(is_bichrome(&path, &path));
+        assert!(!is_bichrome(&path, &path.with_file_name("zoom.exe")));
+    }
+
+    #[test]
+    fn system_handler_refuses_files() {
+        let handler = |_: &str| -> Result<PathBuf> { panic!("looked up the handler") };
+        let error =
+            open_with_system_handler("file:///C:/Users/me/report.pdf", true, handler).unwrap_err();
+        assert!(error.to_string().contains("file: URLs"));
+    }
+
+    #[test]
+    fn system_handler_is_missing() {
+        let handler = |_: &str| Err(anyhow!("not registered"));
+        assert!(
+            open_with_system_handler("zoommtg://zoom.us/join?confno=123", true, handler).is_err()
+        );
+    }
+}